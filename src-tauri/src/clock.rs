@@ -0,0 +1,113 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+pub const DEFAULT_MAX_SKEW: Duration = Duration::from_secs(30);
+
+/// Current local time as milliseconds since the UNIX epoch, the unit used for trigger timestamps.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time after UNIX epoch")
+        .as_millis() as u64
+}
+
+/// What to do with incoming timestamps which are too far away from our local clock.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum SkewPolicy {
+    Clamp,
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkewDecision {
+    Accepted(u64),
+    Clamped(u64),
+    Dropped,
+}
+
+/// Validates timestamps of remote peers against the local clock.
+#[derive(Clone, Copy, Debug)]
+pub struct SkewCheck {
+    pub max_skew: Duration,
+    pub policy: SkewPolicy,
+}
+
+impl Default for SkewCheck {
+    fn default() -> Self {
+        Self {
+            max_skew: DEFAULT_MAX_SKEW,
+            policy: SkewPolicy::Clamp,
+        }
+    }
+}
+
+impl SkewCheck {
+    /// Checks the timestamp of an incoming trigger, replays of a peer's history keep their
+    /// original timestamps no matter how old they are.
+    pub fn check_trigger(&self, timestamp: u64, replay: bool) -> SkewDecision {
+        if replay {
+            return SkewDecision::Accepted(timestamp);
+        }
+        self.check(timestamp)
+    }
+
+    pub fn check(&self, timestamp: u64) -> SkewDecision {
+        let now = now_millis();
+        if now.abs_diff(timestamp) <= self.max_skew.as_millis() as u64 {
+            return SkewDecision::Accepted(timestamp);
+        }
+
+        match self.policy {
+            SkewPolicy::Clamp => SkewDecision::Clamped(now),
+            SkewPolicy::Drop => SkewDecision::Dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An hour ago, far beyond the allowed skew.
+    fn old_timestamp() -> u64 {
+        now_millis() - Duration::from_secs(3600).as_millis() as u64
+    }
+
+    fn skew_check(policy: SkewPolicy) -> SkewCheck {
+        SkewCheck {
+            policy,
+            ..SkewCheck::default()
+        }
+    }
+
+    #[test]
+    fn replays_keep_their_timestamp_when_clamping() {
+        let skew_check = skew_check(SkewPolicy::Clamp);
+        let timestamp = old_timestamp();
+
+        assert_eq!(
+            skew_check.check_trigger(timestamp, true),
+            SkewDecision::Accepted(timestamp)
+        );
+        assert!(matches!(
+            skew_check.check_trigger(timestamp, false),
+            SkewDecision::Clamped(_)
+        ));
+    }
+
+    #[test]
+    fn replays_keep_their_timestamp_when_dropping() {
+        let skew_check = skew_check(SkewPolicy::Drop);
+        let timestamp = old_timestamp();
+
+        assert_eq!(
+            skew_check.check_trigger(timestamp, true),
+            SkewDecision::Accepted(timestamp)
+        );
+        assert_eq!(
+            skew_check.check_trigger(timestamp, false),
+            SkewDecision::Dropped
+        );
+    }
+}
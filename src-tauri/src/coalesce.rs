@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use p2panda_core::PublicKey;
+
+/// Number of remembered triggers after which entries outside of the window are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Collapses repeated triggers of the same sample by the same peer which are closer together than
+/// a few milliseconds, smoothing out double-taps and bouncing buttons.
+///
+/// Disabled by default, every trigger passes until a window is set.
+#[derive(Debug, Default)]
+pub struct Coalesce {
+    window: Option<Duration>,
+    last: HashMap<(PublicKey, u16), u64>,
+}
+
+impl Coalesce {
+    pub fn set_window(&mut self, window: Option<Duration>) {
+        self.window = window;
+        self.last.clear();
+    }
+
+    /// Returns `false` if the trigger falls into the window of the previous one and should be
+    /// collapsed into it.
+    pub fn accept(&mut self, public_key: PublicKey, sample_index: u16, timestamp: u64) -> bool {
+        let Some(window) = self.window else {
+            return true;
+        };
+        let window = window.as_millis() as u64;
+
+        if let Some(last) = self.last.get(&(public_key, sample_index)) {
+            if last.abs_diff(timestamp) <= window {
+                return false;
+            }
+        }
+
+        if self.last.len() >= PRUNE_THRESHOLD {
+            self.last
+                .retain(|_, last| last.abs_diff(timestamp) <= window);
+        }
+        self.last.insert((public_key, sample_index), timestamp);
+
+        true
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use thiserror::Error;
+
+/// Payloads smaller than this are sent as-is, compressing them isn't worth the overhead.
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+const UNCOMPRESSED: u8 = 0;
+const DEFLATE: u8 = 1;
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("payload is empty")]
+    Empty,
+
+    #[error("unknown compression flag {0}")]
+    UnknownFlag(u8),
+
+    #[error("payload exceeds the size limit of {0} bytes")]
+    TooLarge(usize),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Prefixes the payload with a one-byte header flag, compressing it if it exceeds the threshold.
+pub fn compress(bytes: Vec<u8>) -> Vec<u8> {
+    if bytes.len() < COMPRESSION_THRESHOLD {
+        let mut payload = Vec::with_capacity(bytes.len() + 1);
+        payload.push(UNCOMPRESSED);
+        payload.extend(bytes);
+        return payload;
+    }
+
+    let mut encoder = DeflateEncoder::new(vec![DEFLATE], Compression::default());
+    encoder
+        .write_all(&bytes)
+        .expect("write into in-memory encoder");
+    encoder.finish().expect("finish in-memory encoder")
+}
+
+/// Inspects the header flag of a payload and decompresses it if required.
+///
+/// Fails if the payload is larger than `limit` bytes once decompressed, without inflating more
+/// than that.
+pub fn decompress(payload: &[u8], limit: usize) -> Result<Vec<u8>, CompressionError> {
+    let Some((flag, bytes)) = payload.split_first() else {
+        return Err(CompressionError::Empty);
+    };
+
+    let decoded = match *flag {
+        UNCOMPRESSED => bytes.to_vec(),
+        DEFLATE => {
+            let mut decoded = Vec::new();
+            DeflateDecoder::new(bytes)
+                .take(limit as u64 + 1)
+                .read_to_end(&mut decoded)?;
+            decoded
+        }
+        flag => return Err(CompressionError::UnknownFlag(flag)),
+    };
+
+    if decoded.len() > limit {
+        return Err(CompressionError::TooLarge(limit));
+    }
+
+    Ok(decoded)
+}
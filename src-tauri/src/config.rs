@@ -0,0 +1,154 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use p2panda_core::Hash;
+
+use crate::discovery::CustomDiscovery;
+use crate::history::DEFAULT_HISTORY_CAPACITY;
+use crate::sync::SyncProtocolKind;
+use crate::NETWORK_ID;
+
+/// Seconds between two heartbeats by default.
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 5;
+
+/// Seconds to wait for peers before reporting that none were found by default.
+pub const DEFAULT_DISCOVERY_TIMEOUT_SECS: u64 = 15;
+
+/// Largest message we publish or accept by default, in bytes.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// Node configuration, read from environment variables on startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Maximum number of triggers kept in the history, set via `HISTORY_CAPACITY`.
+    pub history_capacity: usize,
+
+    /// Directory the history is saved to on shutdown and restored from on start, `None` only
+    /// keeps it in memory. Can't be set from the environment.
+    pub history_dir: Option<PathBuf>,
+
+    /// Name which scopes local discovery to builds using the same name, set via `MDNS_NAME`.
+    pub mdns_name: Option<String>,
+
+    /// Whether peers are discovered via mdns, disabled by setting `DISABLE_MDNS`.
+    pub mdns: bool,
+
+    /// Discovery mechanism used besides mdns, for example a static peer list in tests. Can't be
+    /// set from the environment.
+    pub custom_discovery: Option<CustomDiscovery>,
+
+    /// Run as an always-on seed peer without frontend which never publishes, set via `SEED`.
+    pub seed: bool,
+
+    /// Start as a listener which receives triggers but never publishes, set via `LISTENER`.
+    pub listener: bool,
+
+    /// Never build a network, published triggers are only echoed locally, set via `LOCAL_ONLY`.
+    pub local_only: bool,
+
+    /// How often we announce being alive, set in seconds via `HEARTBEAT_INTERVAL`, 0 disables
+    /// heartbeats.
+    pub heartbeat_interval: Option<Duration>,
+
+    /// Only one frontend channel is active at a time, a new one replaces the previous one, set
+    /// via `SINGLE_WINDOW`.
+    pub single_window: bool,
+
+    /// Maximum number of gossip neighbours, set via `MAX_PEERS`, unlimited by default.
+    pub max_peers: Option<usize>,
+
+    /// Sync protocol the network runs, picked by name via `SYNC_PROTOCOL`, "dummy" by default.
+    pub sync_protocol: SyncProtocolKind,
+
+    /// Largest message in bytes, before compression, we publish or accept, set via
+    /// `MAX_PAYLOAD_SIZE`, 64 KiB by default.
+    pub max_payload_size: usize,
+
+    /// How long to wait for peers before reporting that none were found, set in seconds via
+    /// `DISCOVERY_TIMEOUT`, 0 disables the report.
+    pub discovery_timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    /// The configuration when none of the environment variables are set.
+    fn default() -> Self {
+        Self {
+            history_capacity: DEFAULT_HISTORY_CAPACITY,
+            history_dir: None,
+            mdns_name: None,
+            mdns: true,
+            custom_discovery: None,
+            seed: false,
+            listener: false,
+            local_only: false,
+            heartbeat_interval: Some(Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS)),
+            single_window: false,
+            max_peers: None,
+            sync_protocol: SyncProtocolKind::default(),
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            discovery_timeout: Some(Duration::from_secs(DEFAULT_DISCOVERY_TIMEOUT_SECS)),
+        }
+    }
+}
+
+impl Config {
+    /// The default configuration, with every value set in the environment overriding it.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            history_capacity: parse_var("HISTORY_CAPACITY").unwrap_or(defaults.history_capacity),
+            mdns_name: env::var("MDNS_NAME").ok().filter(|name| !name.is_empty()),
+            mdns: !flag_var("DISABLE_MDNS"),
+            seed: flag_var("SEED"),
+            listener: flag_var("LISTENER"),
+            local_only: flag_var("LOCAL_ONLY"),
+            heartbeat_interval: secs_var("HEARTBEAT_INTERVAL")
+                .unwrap_or(defaults.heartbeat_interval),
+            single_window: flag_var("SINGLE_WINDOW"),
+            max_peers: parse_var("MAX_PEERS"),
+            sync_protocol: sync_protocol_var("SYNC_PROTOCOL"),
+            max_payload_size: parse_var("MAX_PAYLOAD_SIZE").unwrap_or(defaults.max_payload_size),
+            discovery_timeout: secs_var("DISCOVERY_TIMEOUT").unwrap_or(defaults.discovery_timeout),
+            ..defaults
+        }
+    }
+
+    /// Network id to build the network with.
+    ///
+    /// `LocalDiscovery` doesn't allow configuring its service name, instead we derive a distinct
+    /// network id from the mdns name. Nodes only discover each other when their network ids match.
+    pub fn network_id(&self) -> [u8; 32] {
+        match self.mdns_name {
+            Some(ref name) => *Hash::new(name.as_bytes()).as_bytes(),
+            None => NETWORK_ID,
+        }
+    }
+}
+
+/// Flags are set when the variable is present, unless it is explicitly set to "0" or "false".
+fn flag_var(key: &str) -> bool {
+    env::var(key).is_ok_and(|value| value != "0" && value != "false")
+}
+
+/// Unknown protocol names fall back to the default protocol instead of failing startup.
+fn sync_protocol_var(key: &str) -> SyncProtocolKind {
+    let Ok(name) = env::var(key) else {
+        return SyncProtocolKind::default();
+    };
+
+    name.parse().unwrap_or_else(|err| {
+        println!("{err}, falling back to the default");
+        SyncProtocolKind::default()
+    })
+}
+
+/// Durations are set in seconds, 0 disables whatever they configure.
+fn secs_var(key: &str) -> Option<Option<Duration>> {
+    parse_var(key).map(|secs| (secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+fn parse_var<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|value| value.parse().ok())
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+
+use p2panda_core::Hash;
+
+/// Number of recently seen messages remembered for deduplication.
+pub const DEDUP_CAPACITY: usize = 4096;
+
+/// Remembers hashes of recently received payloads to detect duplicates.
+///
+/// Gossip can deliver the same message more than once through different paths, and sync resends
+/// the same messages every session. Once the capacity is reached, the least recently seen hashes
+/// are forgotten first, seeing a duplicate again counts as seeing it.
+#[derive(Debug)]
+pub struct Dedup {
+    capacity: usize,
+    /// Hashes with the position they were last seen at.
+    seen: HashMap<Hash, u64>,
+    /// Hashes in the order they were seen, entries whose position is outdated are skipped.
+    order: VecDeque<(Hash, u64)>,
+    position: u64,
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self::new(DEDUP_CAPACITY)
+    }
+}
+
+impl Dedup {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            position: 0,
+        }
+    }
+
+    /// Returns `true` if the payload was not seen before and remembers it.
+    pub fn insert(&mut self, payload: &[u8]) -> bool {
+        let hash = Hash::new(payload);
+        self.position += 1;
+        let is_new = self.seen.insert(hash, self.position).is_none();
+        self.order.push_back((hash, self.position));
+
+        if is_new && self.seen.len() > self.capacity {
+            self.evict_least_recent();
+        }
+        // Refreshed hashes leave outdated entries behind, don't let them pile up.
+        if self.order.len() > 2 * self.capacity {
+            let seen = &self.seen;
+            self.order
+                .retain(|(hash, position)| seen.get(hash) == Some(position));
+        }
+
+        is_new
+    }
+
+    fn evict_least_recent(&mut self) {
+        while let Some((hash, position)) = self.order.pop_front() {
+            if self.seen.get(&hash) == Some(&position) {
+                self.seen.remove(&hash);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(index: usize) -> Vec<u8> {
+        format!("message {index}").into_bytes()
+    }
+
+    #[test]
+    fn duplicates_are_detected() {
+        let mut dedup = Dedup::default();
+
+        assert!(dedup.insert(b"trigger"));
+        assert!(!dedup.insert(b"trigger"));
+        assert!(dedup.insert(b"other trigger"));
+    }
+
+    #[test]
+    fn replayed_message_stays_remembered() {
+        let mut dedup = Dedup::default();
+        let replayed = b"replayed by every sync session";
+        let forgotten = b"seen only once";
+
+        assert!(dedup.insert(replayed));
+        assert!(dedup.insert(forgotten));
+        for index in 0..DEDUP_CAPACITY {
+            // Resent halfway through, which refreshes it.
+            if index == DEDUP_CAPACITY / 2 {
+                assert!(!dedup.insert(replayed));
+            }
+            assert!(dedup.insert(&message(index)));
+        }
+
+        // 4096 other messages arrived since both were first seen, only the replayed one was seen
+        // recently enough to be remembered.
+        assert!(!dedup.insert(replayed));
+        assert!(dedup.insert(forgotten));
+    }
+}
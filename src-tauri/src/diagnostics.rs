@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use p2panda_core::PublicKey;
+use serde::Serialize;
+
+use crate::discovery::NetworkInterface;
+
+/// Discovery is considered working when it reported a peer within this window.
+pub const DISCOVERY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Snapshot of the node's networking state, meant to be pasted into bug reports.
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostics {
+    pub public_key: PublicKey,
+    /// Hex encoded id of the network we are part of.
+    pub network_id: String,
+    /// Hex encoded id of the topic we are subscribed to.
+    pub topic: String,
+    /// Local socket addresses the network endpoint is bound to.
+    pub listening_addresses: Vec<String>,
+    /// Names of the active discovery mechanisms.
+    pub discovery: Vec<&'static str>,
+    /// Interfaces of this machine, mdns multicasts on the ones the operating system picks.
+    pub interfaces: Vec<NetworkInterface>,
+    /// Name of the sync protocol the network runs.
+    pub sync_protocol: &'static str,
+    /// Url of the relay we are connected to, `None` when no relay is in use.
+    pub relay: Option<String>,
+    /// Whether the node runs local-only and never touches the network.
+    pub local_only: bool,
+    pub peer_count: usize,
+    /// Unix time in milliseconds discovery last reported a peer.
+    pub last_peer_discovered_at: Option<u64>,
+    /// Whether discovery reported a peer within the last 60 seconds.
+    pub discovery_active: bool,
+}
+
+/// Stages of the self test, in the order they run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum SelfTestStage {
+    NetworkBuilt,
+    Subscribed,
+    TopicReady,
+    /// A published message came back through the local echo path.
+    PublishRoundTrip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum StageOutcome {
+    Passed,
+    Failed,
+    /// The stage doesn't apply, for example the network stages of a local-only node.
+    Skipped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StageReport {
+    pub stage: SelfTestStage,
+    pub outcome: StageOutcome,
+    /// Why the stage failed or was skipped.
+    pub detail: Option<String>,
+}
+
+impl StageReport {
+    pub fn passed(stage: SelfTestStage) -> Self {
+        Self {
+            stage,
+            outcome: StageOutcome::Passed,
+            detail: None,
+        }
+    }
+
+    pub fn failed(stage: SelfTestStage, detail: impl Into<String>) -> Self {
+        Self {
+            stage,
+            outcome: StageOutcome::Failed,
+            detail: Some(detail.into()),
+        }
+    }
+
+    pub fn skipped(stage: SelfTestStage, detail: impl Into<String>) -> Self {
+        Self {
+            stage,
+            outcome: StageOutcome::Skipped,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+/// Result of a self test, telling whether the app itself works apart from finding peers.
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTestReport {
+    /// Whether no stage failed.
+    pub passed: bool,
+    pub stages: Vec<StageReport>,
+}
+
+impl SelfTestReport {
+    pub fn new(stages: Vec<StageReport>) -> Self {
+        Self {
+            passed: stages
+                .iter()
+                .all(|stage| stage.outcome != StageOutcome::Failed),
+            stages,
+        }
+    }
+}
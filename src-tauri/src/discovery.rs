@@ -0,0 +1,97 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use p2panda_core::PublicKey;
+use p2panda_discovery::Discovery;
+use p2panda_net::NetworkBuilder;
+use serde::Serialize;
+
+use crate::AppTopic;
+
+type AddDiscovery = dyn Fn(NetworkBuilder<AppTopic>) -> NetworkBuilder<AppTopic> + Send + Sync;
+
+/// Discovery mechanism the network uses in addition to, or with `DISABLE_MDNS` instead of, mdns.
+///
+/// The network is rebuilt on every reconnect, so instead of a discovery instance this holds a
+/// function creating a fresh one each time.
+#[derive(Clone)]
+pub struct CustomDiscovery {
+    name: &'static str,
+    add: Arc<AddDiscovery>,
+}
+
+impl CustomDiscovery {
+    /// Discovery built by `make`, reported as `name` in the logs and diagnostics.
+    pub fn new<D, F>(name: &'static str, make: F) -> Self
+    where
+        D: Discovery + 'static,
+        F: Fn() -> D + Send + Sync + 'static,
+    {
+        Self {
+            name,
+            add: Arc::new(move |builder| builder.discovery(make())),
+        }
+    }
+
+    /// Fixed list of peers and the addresses they are reachable at, reported as "static".
+    ///
+    /// For networks where multicast doesn't work, like most CI runners and many VPNs. The peers
+    /// are only connected to, they don't need a static entry for this node to accept it.
+    pub fn static_peers(peers: Vec<(PublicKey, Vec<SocketAddr>)>) -> Self {
+        Self {
+            name: "static",
+            add: Arc::new(move |builder| {
+                peers
+                    .iter()
+                    .fold(builder, |builder, (public_key, addresses)| {
+                        builder.direct_address(*public_key, addresses.clone(), None)
+                    })
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(crate) fn add_to(&self, builder: NetworkBuilder<AppTopic>) -> NetworkBuilder<AppTopic> {
+        (self.add)(builder)
+    }
+}
+
+impl fmt::Debug for CustomDiscovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CustomDiscovery").field(&self.name).finish()
+    }
+}
+
+/// Network interface of this machine which mdns might multicast on.
+#[derive(Clone, Debug, Serialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub address: IpAddr,
+    pub loopback: bool,
+}
+
+/// All interfaces with an address, empty if they can't be listed.
+///
+/// mdns doesn't allow picking interfaces, it binds to the unspecified address and the operating
+/// system picks which interfaces to multicast on. With VPNs this is often the tunnel instead of
+/// the LAN, listing the interfaces helps telling when that's the case.
+pub fn network_interfaces() -> Vec<NetworkInterface> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .map(|interface| NetworkInterface {
+                address: interface.ip(),
+                loopback: interface.is_loopback(),
+                name: interface.name,
+            })
+            .collect(),
+        Err(err) => {
+            println!("failed to list network interfaces: {err}");
+            Vec::new()
+        }
+    }
+}
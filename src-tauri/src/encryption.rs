@@ -0,0 +1,127 @@
+use std::fmt;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Header flag of encrypted messages. Plaintext messages start with one of the compression flags,
+/// which never collide with it.
+const ENCRYPTED: u8 = 0xe0;
+
+const NONCE_LEN: usize = 12;
+
+/// Salt room secrets are stretched with. It is the same for everyone, as all peers knowing the
+/// secret need to arrive at the same keys, but it keeps precomputed tables of other apps useless.
+const ROOM_SECRET_SALT: &[u8] = b"can-you-peer-me room secret";
+
+/// Context the encryption key of a room is derived from its secret in.
+const ENCRYPTION_KEY_CONTEXT: &str = "can-you-peer-me v1 room encryption key";
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("message is encrypted but the room has no encryption key")]
+    MissingKey,
+
+    #[error("message is not encrypted but the room requires it")]
+    Unencrypted,
+
+    #[error("encrypted message is truncated")]
+    Truncated,
+
+    #[error("message can't be decrypted with the room's key")]
+    Decrypt,
+}
+
+/// Key everything protecting a private room is derived from: its topic, network id and
+/// encryption key.
+///
+/// The secret is stretched with Argon2id first, so guessing weak passphrases from a topic or
+/// network id, which we announce to everyone, takes real work for every guess. Each key is then
+/// derived from the result with BLAKE3 in its own context, none of them reveals the others.
+pub struct RoomSecret([u8; 32]);
+
+impl RoomSecret {
+    pub fn new(secret: &str) -> Self {
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(secret.as_bytes(), ROOM_SECRET_SALT, &mut key)
+            .expect("default argon2 parameters accept a 32 byte key and our salt");
+        Self(key)
+    }
+
+    /// Derives the key used for one purpose, every purpose needs its own `context`.
+    pub fn derive_key(&self, context: &str) -> [u8; 32] {
+        blake3::derive_key(context, &self.0)
+    }
+}
+
+impl fmt::Debug for RoomSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RoomSecret(..)")
+    }
+}
+
+/// Symmetric key messages of a private room are encrypted with.
+///
+/// Derived from the room's secret, just like its network id, so everyone who knows the secret
+/// can read the room's messages, and nobody else, even when they guessed the topic id.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomKey([u8; 32]);
+
+impl RoomKey {
+    pub fn from_secret(secret: &RoomSecret) -> Self {
+        Self(secret.derive_key(ENCRYPTION_KEY_CONTEXT))
+    }
+}
+
+impl fmt::Debug for RoomKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Rooms end up in logs, their key must not.
+        f.write_str("RoomKey(..)")
+    }
+}
+
+/// Encrypts an encoded message with ChaCha20-Poly1305 and a random nonce, which is prepended to
+/// the ciphertext. Without a key the message is returned as-is.
+pub fn seal(key: Option<&RoomKey>, bytes: Vec<u8>) -> Vec<u8> {
+    let Some(key) = key else {
+        return bytes;
+    };
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.0));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, bytes.as_slice())
+        .expect("encrypt message");
+
+    let mut sealed = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+    sealed.push(ENCRYPTED);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend(ciphertext);
+    sealed
+}
+
+/// Decrypts a received message, the counterpart of `seal`.
+///
+/// Rooms with a key only accept encrypted messages and rooms without one only plaintext ones.
+pub fn open(key: Option<&RoomKey>, bytes: Vec<u8>) -> Result<Vec<u8>, EncryptionError> {
+    let encrypted = bytes.first() == Some(&ENCRYPTED);
+    let key = match key {
+        Some(key) if encrypted => key,
+        Some(_) => return Err(EncryptionError::Unencrypted),
+        None if encrypted => return Err(EncryptionError::MissingKey),
+        None => return Ok(bytes),
+    };
+
+    if bytes.len() < 1 + NONCE_LEN {
+        return Err(EncryptionError::Truncated);
+    }
+    let (nonce, ciphertext) = bytes[1..].split_at(NONCE_LEN);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.0));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| EncryptionError::Decrypt)
+}
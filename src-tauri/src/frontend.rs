@@ -0,0 +1,256 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use serde::Serialize;
+use tauri::ipc::Channel;
+
+use crate::messages::{ChannelEvent, DropReason};
+
+/// Maximum number of events which are buffered while no frontend window is registered.
+pub const MAX_BUFFERED_EVENTS: usize = 1024;
+
+/// We warn when no frontend window registered this long after the node started, for example
+/// because it crashed before calling `init`.
+pub const FRONTEND_WAIT_WARNING: Duration = Duration::from_secs(10);
+
+/// What happened to previously registered channels when a new one was registered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Registration {
+    /// The channel was added, all previously registered ones stay active.
+    Added,
+    /// Single-window mode only, the previously active channel was dropped and won't receive
+    /// any events anymore.
+    Replaced,
+}
+
+/// Channels to all frontend windows which events of the node are broadcast to.
+///
+/// A channel becomes invalid when its window closes or the webview reloads. Sending on it fails
+/// then and we drop it, a reloaded window registers a new one via `init`.
+///
+/// In single-window mode only the most recently registered channel is active. Calling `init`
+/// again, for example from a component mounted twice, replaces the previous channel.
+///
+/// While no window is registered events are buffered and handed to the next window calling
+/// `init`, the oldest events are dropped when the buffer is full. The next window is told how
+/// many were dropped before it receives the remaining ones, followed by the snapshot of the
+/// node's current state.
+pub struct Frontend {
+    single_window: bool,
+    channels: Vec<Channel<ChannelEvent>>,
+    buffered: VecDeque<ChannelEvent>,
+    dropped: u64,
+}
+
+impl Frontend {
+    pub fn new(single_window: bool) -> Self {
+        Self {
+            single_window,
+            channels: Vec::new(),
+            buffered: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Registers a channel and hands it the buffered events, then the snapshot if there is one.
+    ///
+    /// Buffered triggers which are part of the snapshot are dropped, the window would play them
+    /// twice and out of order otherwise.
+    pub fn add_channel(
+        &mut self,
+        channel: Channel<ChannelEvent>,
+        snapshot: Option<ChannelEvent>,
+    ) -> Registration {
+        let registration = if self.single_window && !self.channels.is_empty() {
+            println!("replacing active app channel");
+            self.channels.clear();
+            Registration::Replaced
+        } else {
+            Registration::Added
+        };
+        self.channels.push(channel.clone());
+
+        if let Some(ChannelEvent::Snapshot {
+            recent_triggers, ..
+        }) = &snapshot
+        {
+            let covered = recent_triggers
+                .iter()
+                .map(|trigger| (trigger.public_key, trigger.seq))
+                .collect::<HashSet<_>>();
+            self.buffered.retain(|event| match event {
+                ChannelEvent::ApplicationMessage(trigger) => {
+                    !covered.contains(&(trigger.public_key, trigger.seq))
+                }
+                _ => true,
+            });
+        }
+
+        if self.dropped > 0 {
+            let count = std::mem::take(&mut self.dropped);
+            self.send(ChannelEvent::Dropped {
+                count,
+                reason: DropReason::NoFrontend,
+            });
+        }
+        for event in std::mem::take(&mut self.buffered) {
+            self.send(event);
+        }
+        // Only the new window missed what happened before, the others are up to date.
+        if let Some(snapshot) = snapshot {
+            if let Err(err) = channel.send(snapshot) {
+                println!("failed to send snapshot on app channel: {err}");
+            }
+        }
+
+        registration
+    }
+
+    pub fn send(&mut self, event: ChannelEvent) {
+        self.channels
+            .retain(|channel| match channel.send(event.clone()) {
+                Ok(()) => true,
+                Err(err) => {
+                    println!("failed to send on app channel, removing it: {err}");
+                    false
+                }
+            });
+
+        if self.channels.is_empty() {
+            if self.buffered.len() == MAX_BUFFERED_EVENTS {
+                self.buffered.pop_front();
+                self.dropped += 1;
+            }
+            self.buffered.push_back(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use p2panda_core::{PrivateKey, PublicKey};
+    use serde_json::Value;
+    use tauri::ipc::InvokeResponseBody;
+
+    use super::*;
+    use crate::messages::ApplicationMessage;
+
+    /// Channel collecting everything sent on it, like a frontend window would receive it.
+    fn recording_channel() -> (Channel<ChannelEvent>, Arc<Mutex<Vec<Value>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let channel = Channel::new({
+            let received = received.clone();
+            move |body| {
+                let event = match body {
+                    InvokeResponseBody::Json(json) => {
+                        serde_json::from_str(&json).expect("parse channel event")
+                    }
+                    InvokeResponseBody::Raw(_) => panic!("channel events are sent as json"),
+                };
+                received.lock().unwrap().push(event);
+                Ok(())
+            }
+        });
+        (channel, received)
+    }
+
+    fn error(message: &str) -> ChannelEvent {
+        ChannelEvent::Error {
+            message: message.to_string(),
+        }
+    }
+
+    fn trigger(public_key: PublicKey, seq: u64) -> ApplicationMessage {
+        ApplicationMessage {
+            public_key,
+            timestamp: seq,
+            sample_index: 1,
+            seq,
+            bank_id: 0,
+            replay: false,
+            topic_id: [0; 32],
+            hue: 0,
+        }
+    }
+
+    fn messages(received: &Mutex<Vec<Value>>) -> Vec<String> {
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                event["message"]
+                    .as_str()
+                    .expect("error message")
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replaced_channel_receives_no_more_events() {
+        let mut frontend = Frontend::new(true);
+        frontend.send(error("before init"));
+
+        let (first, first_received) = recording_channel();
+        assert_eq!(frontend.add_channel(first, None), Registration::Added);
+        assert_eq!(messages(&first_received), vec!["before init"]);
+
+        let (second, second_received) = recording_channel();
+        assert_eq!(frontend.add_channel(second, None), Registration::Replaced);
+        frontend.send(error("after replacing"));
+
+        assert_eq!(messages(&first_received), vec!["before init"]);
+        assert_eq!(messages(&second_received), vec!["after replacing"]);
+    }
+
+    #[test]
+    fn buffered_events_are_flushed_after_the_drop_count() {
+        let mut frontend = Frontend::new(true);
+        for index in 0..=MAX_BUFFERED_EVENTS {
+            frontend.send(error(&index.to_string()));
+        }
+
+        let (channel, received) = recording_channel();
+        assert_eq!(frontend.add_channel(channel, None), Registration::Added);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), MAX_BUFFERED_EVENTS + 1);
+        assert_eq!(received[0]["type"], "Dropped");
+        assert_eq!(received[0]["count"], 1);
+        // The oldest event was dropped, the remaining ones arrive in order.
+        assert_eq!(received[1]["message"], "1");
+        assert_eq!(
+            received[MAX_BUFFERED_EVENTS]["message"],
+            MAX_BUFFERED_EVENTS.to_string()
+        );
+    }
+
+    #[test]
+    fn snapshot_replaces_buffered_triggers_and_arrives_last() {
+        let public_key = PrivateKey::new().public_key();
+        let mut frontend = Frontend::new(false);
+        for seq in 0..3 {
+            frontend.send(ChannelEvent::ApplicationMessage(trigger(public_key, seq)));
+        }
+        frontend.send(error("while unregistered"));
+
+        // The snapshot only holds the most recent triggers, older ones are still forwarded.
+        let snapshot = ChannelEvent::Snapshot {
+            peers: Vec::new(),
+            recent_triggers: vec![trigger(public_key, 1), trigger(public_key, 2)],
+        };
+        let (channel, received) = recording_channel();
+        frontend.add_channel(channel, Some(snapshot));
+
+        let received = received.lock().unwrap();
+        let types = received
+            .iter()
+            .map(|event| event["type"].as_str().expect("event type"))
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec!["ApplicationMessage", "Error", "Snapshot"]);
+        assert_eq!(received[0]["data"]["seq"], 0);
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use p2panda_core::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::messages::ApplicationMessage;
+
+pub const DEFAULT_HISTORY_CAPACITY: usize = 200;
+
+/// Version of the format the history is stored in.
+const SAVED_HISTORY_VERSION: u16 = 1;
+
+/// Trigger in the history, along with the message its author published it in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredTrigger {
+    pub message: ApplicationMessage,
+    /// Signed and encoded as published, so anyone can resend it without the author's key.
+    pub bytes: Vec<u8>,
+}
+
+/// Bounded buffer of the most recent triggers, the oldest ones are dropped once it is full.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    triggers: VecDeque<StoredTrigger>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            triggers: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, trigger: StoredTrigger) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.triggers.len() == self.capacity {
+            self.triggers.pop_front();
+        }
+        self.triggers.push_back(trigger);
+    }
+
+    pub fn clear(&mut self) {
+        self.triggers.clear();
+    }
+
+    pub fn to_messages(&self) -> Vec<ApplicationMessage> {
+        self.recent(self.triggers.len())
+    }
+
+    /// The last `limit` triggers, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<ApplicationMessage> {
+        self.triggers
+            .iter()
+            .skip(self.triggers.len().saturating_sub(limit))
+            .map(|trigger| trigger.message.clone())
+            .collect()
+    }
+
+    /// All triggers with the messages they were published in, oldest first.
+    pub fn to_stored(&self) -> Vec<StoredTrigger> {
+        self.triggers.iter().cloned().collect()
+    }
+
+    /// Whether the trigger of the given peer with this sequence number is in the history.
+    pub fn contains(&self, public_key: PublicKey, seq: u64) -> bool {
+        self.triggers
+            .iter()
+            .any(|trigger| trigger.message.public_key == public_key && trigger.message.seq == seq)
+    }
+
+    /// Triggers of the given peer with sequence numbers from `from` to `to`, inclusive.
+    ///
+    /// Returns `None` if the start of the range was evicted already.
+    pub fn range(&self, public_key: PublicKey, from: u64, to: u64) -> Option<Vec<StoredTrigger>> {
+        let mut triggers = self
+            .triggers
+            .iter()
+            .filter(|trigger| trigger.message.public_key == public_key)
+            .peekable();
+
+        match triggers.peek() {
+            Some(oldest) if oldest.message.seq <= from => (),
+            _ => return None,
+        }
+
+        Some(
+            triggers
+                .filter(|trigger| (from..=to).contains(&trigger.message.seq))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedHistory {
+    version: u16,
+    triggers: Vec<StoredTrigger>,
+}
+
+/// Keeps the history across restarts.
+#[derive(Clone, Debug)]
+pub struct HistoryStore {
+    path: PathBuf,
+}
+
+impl HistoryStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            path: dir.join("history.json"),
+        }
+    }
+
+    /// Triggers saved by the previous run, oldest first, empty if none were saved or they can't
+    /// be read.
+    pub fn load(&self) -> Vec<StoredTrigger> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Vec::new(),
+            Err(err) => {
+                println!("failed to read saved history, starting empty: {err}");
+                return Vec::new();
+            }
+        };
+
+        match serde_json::from_slice::<SavedHistory>(&bytes) {
+            Ok(saved) if saved.version == SAVED_HISTORY_VERSION => saved.triggers,
+            Ok(saved) => {
+                println!(
+                    "saved history has incompatible version {}, starting empty",
+                    saved.version
+                );
+                Vec::new()
+            }
+            Err(err) => {
+                println!("failed to parse saved history, starting empty: {err}");
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn save(&self, triggers: Vec<StoredTrigger>) -> anyhow::Result<()> {
+        let saved = SavedHistory {
+            version: SAVED_HISTORY_VERSION,
+            triggers,
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context("creating data directory")?;
+        }
+
+        // Replacing the file in one step, so being killed while writing leaves the previously
+        // saved history in place instead of a truncated one.
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&saved)?).context("writing saved history")?;
+        fs::rename(&tmp_path, &self.path).context("replacing saved history")?;
+        Ok(())
+    }
+
+    /// Removes the saved history, so cleared triggers don't come back on the next start.
+    pub fn clear(&self) -> anyhow::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).context("removing saved history")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::PrivateKey;
+
+    use super::*;
+
+    fn trigger(public_key: PublicKey, seq: u64) -> StoredTrigger {
+        StoredTrigger {
+            message: ApplicationMessage {
+                public_key,
+                timestamp: seq,
+                sample_index: 1,
+                seq,
+                bank_id: 0,
+                replay: false,
+                topic_id: [0; 32],
+                hue: 0,
+            },
+            bytes: seq.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn seqs(triggers: &[StoredTrigger]) -> Vec<u64> {
+        triggers.iter().map(|trigger| trigger.message.seq).collect()
+    }
+
+    #[test]
+    fn oldest_triggers_are_evicted() {
+        let public_key = PrivateKey::new().public_key();
+        let mut history = History::new(3);
+        for seq in 0..5 {
+            history.push(trigger(public_key, seq));
+        }
+
+        assert_eq!(seqs(&history.to_stored()), vec![2, 3, 4]);
+        assert!(!history.contains(public_key, 1));
+        assert!(history.contains(public_key, 2));
+    }
+
+    #[test]
+    fn range_is_unavailable_once_its_start_was_evicted() {
+        let public_key = PrivateKey::new().public_key();
+        let other = PrivateKey::new().public_key();
+        let mut history = History::new(4);
+        for seq in 0..4 {
+            history.push(trigger(public_key, seq));
+        }
+        history.push(trigger(other, 0));
+
+        let range = history
+            .range(public_key, 1, 2)
+            .expect("range still in history");
+        assert_eq!(seqs(&range), vec![1, 2]);
+        assert!(history.range(public_key, 0, 2).is_none());
+        assert!(history
+            .range(PrivateKey::new().public_key(), 0, 0)
+            .is_none());
+    }
+
+    #[test]
+    fn saved_triggers_are_loaded_until_cleared() {
+        let dir = std::env::temp_dir().join(format!(
+            "can-you-peer-me-history-{}",
+            PrivateKey::new().public_key()
+        ));
+        let store = HistoryStore::new(dir.clone());
+        let public_key = PrivateKey::new().public_key();
+        assert!(store.load().is_empty());
+
+        store
+            .save(vec![trigger(public_key, 0), trigger(public_key, 1)])
+            .expect("save history");
+        let loaded = store.load();
+        assert_eq!(seqs(&loaded), vec![0, 1]);
+        assert_eq!(loaded[1].bytes, 1u64.to_be_bytes());
+
+        store.clear().expect("clear history");
+        assert!(store.load().is_empty());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
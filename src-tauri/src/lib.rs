@@ -1,28 +1,63 @@
+mod clock;
+mod coalesce;
+mod compression;
+mod config;
+mod dedup;
+mod diagnostics;
+mod discovery;
+mod encryption;
+mod frontend;
+mod history;
 mod messages;
+mod metrics;
+mod node;
+mod peers;
+mod resync;
+mod retry;
+mod room;
+mod state;
 mod sync;
+mod sync_health;
+mod throttle;
+mod wire;
 
 use std::hash::Hash as StdHash;
+use std::time::Duration;
 
-use p2panda_core::cbor::{decode_cbor, encode_cbor};
-use p2panda_core::PrivateKey;
-use p2panda_discovery::mdns::LocalDiscovery;
-use p2panda_net::{
-    FromNetwork, Network, NetworkBuilder, ResyncConfiguration, SyncConfiguration, ToNetwork,
-    TopicId,
-};
+use p2panda_core::{PrivateKey, PublicKey};
+use p2panda_net::TopicId;
 use p2panda_sync::TopicQuery;
 use serde::{Deserialize, Serialize};
 use tauri::ipc::Channel;
-use tauri::{App, Builder, Error, Manager, State};
-use tokio::sync::{mpsc, Mutex};
+use tauri::{App, AppHandle, Builder, Error, Manager, RunEvent, State};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+
+use frontend::{Frontend, Registration, FRONTEND_WAIT_WARNING};
+use room::RoomStore;
 
-use messages::{ApplicationMessage, ChannelEvent, SystemEvent};
+pub use clock::{SkewCheck, SkewPolicy};
+pub use config::Config;
+pub use diagnostics::{Diagnostics, SelfTestReport, SelfTestStage, StageOutcome, StageReport};
+pub use discovery::{CustomDiscovery, NetworkInterface};
+pub use encryption::RoomSecret;
+pub use messages::{
+    peer_hue, ApplicationMessage, ChannelEvent, ControlKind, Delivery, DropReason, EchoMode, Role,
+    SystemEvent,
+};
+pub use metrics::{MessageRate, QueueDepth};
+pub use node::{Node, NodeStopped, PublishError};
+pub use peers::{PeerQuality, PeerState, Presence};
+pub use room::{Room, RoomInfo};
+pub use state::AppStateSnapshot;
+pub use sync::SyncProtocolKind;
+pub use sync_health::{PeerSyncHealth, SyncOutcome};
 
 static NETWORK_ID: [u8; 32] = [0; 32];
 static APP_TOPIC: AppTopic = AppTopic([1; 32]);
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, StdHash, Serialize, Deserialize)]
-struct AppTopic([u8; 32]);
+pub struct AppTopic([u8; 32]);
 
 impl TopicId for AppTopic {
     fn id(&self) -> [u8; 32] {
@@ -33,150 +68,712 @@ impl TopicId for AppTopic {
 impl TopicQuery for AppTopic {}
 
 struct AppContext {
-    channel_init_tx: mpsc::Sender<Channel<ChannelEvent>>,
-    #[allow(dead_code)]
-    network: Network<AppTopic>,
-    topic_tx: mpsc::Sender<ToNetwork>,
-    app_tx: mpsc::Sender<(u64, u16)>,
+    channel_init_tx: mpsc::Sender<(Channel<ChannelEvent>, oneshot::Sender<Registration>)>,
+    node: Node,
+    /// `None` when the app config directory couldn't be resolved.
+    room_store: Option<RoomStore>,
+}
+
+impl AppContext {
+    /// Remembers the room to rejoin after a restart, the default room is forgotten.
+    fn save_room(&self, room: &Room) {
+        let Some(ref room_store) = self.room_store else {
+            return;
+        };
+
+        let result = if *room == Room::default() {
+            room_store.clear()
+        } else {
+            room_store.save(room)
+        };
+        if let Err(err) = result {
+            println!("failed to save room: {err:#}");
+        }
+    }
 }
 
+/// Registers a channel the node's events are sent to.
+///
+/// By default every window gets its own channel and all of them receive all events. When
+/// running with `SINGLE_WINDOW` a new channel replaces the active one, which receives no events
+/// anymore, this is reported as `Replaced`.
 #[tauri::command]
 async fn init(
-    state: State<'_, Mutex<AppContext>>,
+    state: State<'_, AppContext>,
     channel: Channel<ChannelEvent>,
-) -> Result<(), Error> {
-    let state = state.lock().await;
+) -> Result<Registration, Error> {
+    let (reply, reply_rx) = oneshot::channel();
     state
         .channel_init_tx
-        .send(channel)
+        .send((channel, reply))
         .await
-        .expect("send on init channel");
-
-    Ok(())
+        .map_err(|_| anyhow::anyhow!("event forwarding stopped, channel not registered"))?;
+    let registration = reply_rx
+        .await
+        .map_err(|_| anyhow::anyhow!("event forwarding stopped, channel not registered"))?;
+    Ok(registration)
 }
 
+/// Publishes a trigger, via gossip unless another delivery is requested.
+///
+/// Without a timestamp the node stamps the trigger at send time. Explicit timestamps, for
+/// example of scheduled sends, must lie within the skew threshold set by `set_skew_check`.
+///
+/// Returns how many more messages can be published right now. When the node can't keep up the
+/// trigger is rejected with a backpressure error instead of waiting.
 #[tauri::command]
 async fn publish(
-    state: State<'_, Mutex<AppContext>>,
-    timestamp: u64,
+    state: State<'_, AppContext>,
+    timestamp: Option<u64>,
     index: u16,
+    delivery: Option<Delivery>,
+) -> Result<usize, Error> {
+    let remaining = state
+        .node
+        .publish(timestamp, index, delivery.unwrap_or_default())
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(remaining)
+}
+
+/// Publishes arbitrary bytes, for apps which bring their own payload format.
+///
+/// Peers receive them as `RawMessage` events, the trigger handling is bypassed completely.
+/// Returns how many more messages can be published right now, like `publish`.
+#[tauri::command]
+async fn publish_bytes(
+    state: State<'_, AppContext>,
+    bytes: Vec<u8>,
+    delivery: Option<Delivery>,
+) -> Result<usize, Error> {
+    let remaining = state
+        .node
+        .publish_bytes(bytes, delivery.unwrap_or_default())
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(remaining)
+}
+
+/// Asks the given peer to resend its triggers with sequence numbers from `from` to `to`.
+///
+/// The triggers arrive as regular `ApplicationMessage`s once the peer synced them to us. An
+/// `Error` event follows instead if the range was evicted from the peer's history.
+#[tauri::command]
+async fn request_range(
+    state: State<'_, AppContext>,
+    peer: String,
+    from: u64,
+    to: u64,
 ) -> Result<(), Error> {
-    let state = state.lock().await;
-    let message = (timestamp, index);
+    let peer: PublicKey = peer
+        .parse()
+        .map_err(|err| anyhow::anyhow!("invalid public key {peer}: {err}"))?;
+    state
+        .node
+        .request_range(peer, from, to)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Resends all recent triggers, ours and those of others, to a connected peer who missed them,
+/// returns how many.
+///
+/// Fails if the peer isn't one of our neighbours.
+#[tauri::command]
+async fn push_history_to(state: State<'_, AppContext>, peer: String) -> Result<usize, Error> {
+    let peer: PublicKey = peer
+        .parse()
+        .map_err(|err| anyhow::anyhow!("invalid public key {peer}: {err}"))?;
+    let count = state.node.push_history_to(peer).await?;
+    Ok(count)
+}
+
+/// Subscribes to the current topic again when gossip stopped delivering, without reconnecting.
+#[tauri::command]
+async fn resubscribe(state: State<'_, AppContext>) -> Result<(), Error> {
+    state.node.resubscribe().await?;
+    Ok(())
+}
+
+/// Everything the node currently knows, for debugging or restoring the UI from the backend.
+#[tauri::command]
+async fn full_state(state: State<'_, AppContext>) -> Result<AppStateSnapshot, Error> {
+    let snapshot = state.node.full_state().await.map_err(anyhow::Error::from)?;
+    Ok(snapshot)
+}
+
+/// Picks when our own published messages are echoed: `Immediate` right away, the default, or
+/// `Confirmed` once they were handed to the gossip overlay.
+///
+/// Immediate echoes feel instant but can show triggers which are still queued and might never
+/// reach anyone. Confirmed echoes add the delay of handing them over and of waiting for the
+/// topic to become ready.
+#[tauri::command]
+async fn set_echo_mode(state: State<'_, AppContext>, mode: EchoMode) -> Result<(), Error> {
+    state.node.set_echo_mode(mode).await;
+    Ok(())
+}
+
+/// Switches between `Performer` and `Listener`, listeners only receive triggers.
+///
+/// While listening, `publish` and `publish_bytes` fail as read-only and `all_notes_off` does
+/// nothing. Incoming triggers and history sync keep working, and peers see us as a listener in
+/// their presence.
+#[tauri::command]
+async fn set_role(state: State<'_, AppContext>, role: Role) -> Result<(), Error> {
+    state.node.set_role(role).await;
+    Ok(())
+}
+
+/// Limits how fast triggers and raw payloads can be published.
+///
+/// Up to `burst` messages go out at once, after that `rate` messages per second. Excess messages
+/// are rejected with an error. Defaults to 50 per second with bursts of 100.
+///
+/// The rate must be a positive number and the burst at least one, otherwise nothing could ever
+/// be published.
+#[tauri::command]
+async fn set_publish_rate(
+    state: State<'_, AppContext>,
+    rate: f64,
+    burst: u32,
+) -> Result<(), Error> {
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(anyhow::anyhow!("invalid publish rate {rate}, must be positive").into());
+    }
+    if burst == 0 {
+        return Err(anyhow::anyhow!("invalid burst size 0, must be at least 1").into());
+    }
+    state.node.set_publish_rate(rate, burst).await;
+    Ok(())
+}
+
+/// Tells everyone in the session to silence all sounds immediately.
+#[tauri::command]
+async fn all_notes_off(state: State<'_, AppContext>) -> Result<(), Error> {
+    state.node.all_notes_off().await;
+    Ok(())
+}
+
+/// Returns the hex-encoded id of the topic the node is subscribed to.
+#[tauri::command]
+async fn current_topic(state: State<'_, AppContext>) -> Result<String, Error> {
+    let topic = state.node.current_topic().await;
+    Ok(hex::encode(topic.id()))
+}
+
+/// Returns the most recently published and received triggers, oldest first.
+#[tauri::command]
+async fn recent_triggers(state: State<'_, AppContext>) -> Result<Vec<ApplicationMessage>, Error> {
+    Ok(state.node.recent_triggers().await)
+}
+
+/// Removes all triggers from the local history, including the one saved for the next start.
+#[tauri::command]
+async fn clear_history(state: State<'_, AppContext>) -> Result<(), Error> {
     state
-        .app_tx
-        .send(message)
+        .node
+        .clear_history()
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Returns peers which discovery found but which we are not connected to via gossip.
+#[tauri::command]
+async fn discovered_peers(state: State<'_, AppContext>) -> Result<Vec<String>, Error> {
+    let peers = state
+        .node
+        .discovered_peers()
         .await
-        .expect("send on app_tx channel");
+        .map_err(anyhow::Error::from)?;
+    Ok(peers.iter().map(|peer| peer.to_hex()).collect())
+}
+
+/// Returns when we last heard of each peer, heartbeats included, as Unix time in milliseconds.
+#[tauri::command]
+async fn presence(state: State<'_, AppContext>) -> Result<Vec<Presence>, Error> {
+    let presence = state.node.presence().await.map_err(anyhow::Error::from)?;
+    Ok(presence)
+}
+
+/// Returns how many messages are queued on their way to the network, and how many fit.
+#[tauri::command]
+async fn queue_depth(state: State<'_, AppContext>) -> Result<QueueDepth, Error> {
+    Ok(state.node.queue_depth().await)
+}
+
+/// Returns how many messages per second were published and received over the last 5 seconds.
+#[tauri::command]
+async fn message_rate(state: State<'_, AppContext>) -> Result<MessageRate, Error> {
+    Ok(state.node.message_rate())
+}
+
+/// Returns how well we are connected to each of our current gossip neighbours.
+#[tauri::command]
+async fn peer_quality(state: State<'_, AppContext>) -> Result<Vec<PeerQuality>, Error> {
+    let quality = state
+        .node
+        .peer_quality()
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(quality)
+}
+
+/// Only forward incoming triggers for the given sample indices to the frontend.
+///
+/// An empty list removes the filter and all incoming triggers are forwarded again. Our own
+/// published triggers are not affected by the filter.
+#[tauri::command]
+async fn set_index_filter(state: State<'_, AppContext>, indices: Vec<u16>) -> Result<(), Error> {
+    let filter = if indices.is_empty() {
+        None
+    } else {
+        Some(indices.into_iter().collect())
+    };
+
     state
-        .topic_tx
-        .send(ToNetwork::Message {
-            bytes: encode_cbor(&message).expect("encode message"),
+        .node
+        .set_index_filter(filter)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Picks the sound bank our triggers refer to, so peers with different kits don't collide.
+///
+/// Incoming triggers of banks which are neither ours nor in `known_banks` are still forwarded,
+/// but an `UnknownBank` event is emitted once per peer and bank. Bank 0 is the default.
+#[tauri::command]
+async fn set_bank(
+    state: State<'_, AppContext>,
+    bank_id: u16,
+    known_banks: Option<Vec<u16>>,
+) -> Result<(), Error> {
+    let known_banks = known_banks.unwrap_or_default().into_iter().collect();
+    state
+        .node
+        .set_bank(bank_id, known_banks)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Only forward incoming triggers of the given peer to the frontend, `null` clears the solo.
+///
+/// Our own triggers are always forwarded. The solo applies before the index filter, so
+/// triggers of the soloed peer are still subject to it.
+#[tauri::command]
+async fn solo_peer(state: State<'_, AppContext>, public_key: Option<String>) -> Result<(), Error> {
+    let peer = public_key
+        .map(|public_key| {
+            public_key
+                .parse::<PublicKey>()
+                .map_err(|err| anyhow::anyhow!("invalid public key {public_key}: {err}"))
         })
+        .transpose()?;
+
+    state
+        .node
+        .set_solo(peer)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Freezes incoming triggers without leaving the room, `paused: false` resumes.
+///
+/// Unlike `leave_room` we stay connected and keep history and presence up to date. With
+/// `flush_on_resume`, triggers which arrived while paused are forwarded on resume, up to the
+/// most recent 256, otherwise they are skipped.
+#[tauri::command]
+async fn set_receive_paused(
+    state: State<'_, AppContext>,
+    paused: bool,
+    flush_on_resume: Option<bool>,
+) -> Result<(), Error> {
+    state
+        .node
+        .set_receive_paused(paused, flush_on_resume.unwrap_or(false))
         .await
-        .expect("send on topic_tx channel");
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
 
-    println!("message published: {:?}", message);
+/// Configures how far timestamps of incoming triggers may deviate from our local clock.
+///
+/// Timestamps outside of `max_skew_ms` are either clamped to the current time or dropped,
+/// depending on the policy. Peers on a LAN usually have tight clocks, across the internet a
+/// looser threshold might be required. Defaults to 30 seconds and clamping.
+#[tauri::command]
+async fn set_skew_check(
+    state: State<'_, AppContext>,
+    max_skew_ms: u64,
+    policy: SkewPolicy,
+) -> Result<(), Error> {
+    let skew_check = SkewCheck {
+        max_skew: Duration::from_millis(max_skew_ms),
+        policy,
+    };
 
+    state
+        .node
+        .set_skew_check(skew_check)
+        .await
+        .map_err(anyhow::Error::from)?;
     Ok(())
 }
 
+/// Leaves the current room and joins the room with the given name, returning its hex-encoded
+/// topic id.
+///
+/// With a secret the room gets its own network, only peers knowing the secret can discover us.
+/// Without one it shares the network with all other rooms but its messages stay separate. The
+/// history is cleared as it belongs to the previous room.
+///
+/// With `encrypt` set, messages are additionally encrypted with a key derived from the secret,
+/// all peers in the room need to enable it as well.
+#[tauri::command]
+async fn join_room(
+    state: State<'_, AppContext>,
+    name: String,
+    secret: Option<String>,
+    encrypt: Option<bool>,
+) -> Result<String, Error> {
+    let encrypt = encrypt.unwrap_or(false);
+    if encrypt && secret.is_none() {
+        return Err(anyhow::anyhow!("encrypting a room requires a secret").into());
+    }
+    // Stretching the secret is deliberately slow, so it runs off the async runtime, and only once
+    // for all keys of the room.
+    let room = tauri::async_runtime::spawn_blocking(move || {
+        let secret = secret.as_deref().map(RoomSecret::new);
+        let room = Room::new(&name, secret.as_ref());
+        match secret {
+            Some(ref secret) if encrypt => room.encrypted(secret),
+            _ => room,
+        }
+    })
+    .await?;
+    let topic = room.topic;
+    state.node.join_room(room.clone()).await?;
+    state.save_room(&room);
+    Ok(hex::encode(topic.id()))
+}
+
+/// Connects to the private room behind the given passphrase, returning its hex-encoded topic id.
+///
+/// Network id and topic are both derived from the passphrase, the private key stays the same.
+/// The previous network is torn down first. A `Connected` event follows once we joined the
+/// room's gossip overlay.
+///
+/// With `encrypt` set, messages are encrypted with a key derived from the passphrase as well.
+#[tauri::command]
+async fn connect(
+    state: State<'_, AppContext>,
+    passphrase: String,
+    encrypt: Option<bool>,
+) -> Result<String, Error> {
+    let encrypt = encrypt.unwrap_or(false);
+    // Stretching the passphrase is deliberately slow, so it runs off the async runtime.
+    let room =
+        tauri::async_runtime::spawn_blocking(move || Room::from_passphrase(&passphrase, encrypt))
+            .await?;
+    let topic = room.topic;
+    state.node.join_room(room.clone()).await?;
+    state.save_room(&room);
+    Ok(hex::encode(topic.id()))
+}
+
+/// Returns rooms nodes nearby advertised within the last 30 seconds, most recently seen first.
+///
+/// Only rooms joined by name without a secret are advertised.
+#[tauri::command]
+async fn discover_rooms(state: State<'_, AppContext>) -> Result<Vec<RoomInfo>, Error> {
+    let rooms = state
+        .node
+        .discover_rooms()
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(rooms)
+}
+
+/// Leaves the current room and returns to the default one, tearing down its dedicated network.
+#[tauri::command]
+async fn leave_room(state: State<'_, AppContext>) -> Result<(), Error> {
+    state.node.join_room(Room::default()).await?;
+    state.save_room(&Room::default());
+    Ok(())
+}
+
+/// Collapses repeated triggers of the same sample by the same peer within `window_ms` into one.
+///
+/// Smooths out double-taps and bouncing buttons. A window of 0 disables coalescing, which is the
+/// default.
+#[tauri::command]
+async fn set_coalesce_window(state: State<'_, AppContext>, window_ms: u64) -> Result<(), Error> {
+    let window = (window_ms > 0).then(|| Duration::from_millis(window_ms));
+    state
+        .node
+        .set_coalesce_window(window)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Collects the node's identity, addresses, discovery and peer state in one report.
+///
+/// Meant to be copied into bug reports about peers not finding each other.
+#[tauri::command]
+async fn diagnostics(state: State<'_, AppContext>) -> Result<Diagnostics, Error> {
+    let diagnostics = state
+        .node
+        .diagnostics()
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(diagnostics)
+}
+
+/// Checks whether the app itself works, so it can be told apart from a network blocking peers.
+///
+/// Reports pass or fail for building the network, subscribing to the topic, the topic becoming
+/// ready and a published message being echoed back.
+#[tauri::command]
+async fn self_test(state: State<'_, AppContext>) -> Result<SelfTestReport, Error> {
+    let report = state.node.self_test().await.map_err(anyhow::Error::from)?;
+    Ok(report)
+}
+
+/// Network interfaces of this machine with their addresses.
+///
+/// mdns can't be restricted to some of them, but the list shows whether a VPN tunnel might
+/// capture the multicast traffic meant for the LAN.
+#[tauri::command]
+async fn network_interfaces() -> Result<Vec<NetworkInterface>, Error> {
+    Ok(discovery::network_interfaces())
+}
+
+/// Sync sessions started, completed and failed per peer, with their most recent outcomes.
+///
+/// Helps to tell why history from a peer keeps missing, for example when syncing with it fails
+/// consistently.
+#[tauri::command]
+async fn sync_health(state: State<'_, AppContext>) -> Result<Vec<PeerSyncHealth>, Error> {
+    let health = state
+        .node
+        .sync_health()
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(health)
+}
+
+/// Pauses or resumes pulling in messages from peers during periodic resyncs.
+///
+/// Useful to freeze the session during a live set, gossip stays connected. Peers can still sync
+/// with us while paused, but we don't catch up with anyone, including peers joining meanwhile.
+#[tauri::command]
+async fn set_resync_enabled(state: State<'_, AppContext>, enabled: bool) -> Result<(), Error> {
+    state.node.set_resync_enabled(enabled);
+    Ok(())
+}
+
+/// Caps the number of gossip neighbours, `null` removes the cap.
+///
+/// Evicted and rejected peers are reported as `PeerEvicted` and `PeerRejected` events.
+#[tauri::command]
+async fn set_max_peers(
+    state: State<'_, AppContext>,
+    max_peers: Option<usize>,
+) -> Result<(), Error> {
+    state
+        .node
+        .set_max_peers(max_peers)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Replaces the node's private key with a freshly generated one and returns the new public key.
+///
+/// The network is rebuilt and re-subscribed to the topic, our previous identity leaves the gossip
+/// overlay before the new one joins. Messages which are in-flight during the swap may be lost.
+#[tauri::command]
+async fn rotate_identity(state: State<'_, AppContext>) -> Result<String, Error> {
+    let public_key = state.node.rotate_identity().await?;
+    Ok(public_key.to_hex())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    let config = Config::from_env();
+    if config.seed {
+        run_seed(config);
+        return;
+    }
+
     Builder::default()
         .setup(|app| {
-            spawn_node(app)?;
+            spawn_node(app, config)?;
             Ok(())
         })
         .plugin(tauri_plugin_shell::init())
-        .invoke_handler(tauri::generate_handler![init, publish])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .invoke_handler(tauri::generate_handler![
+            init,
+            publish,
+            publish_bytes,
+            all_notes_off,
+            clear_history,
+            connect,
+            current_topic,
+            diagnostics,
+            discover_rooms,
+            discovered_peers,
+            full_state,
+            join_room,
+            leave_room,
+            message_rate,
+            network_interfaces,
+            peer_quality,
+            presence,
+            push_history_to,
+            queue_depth,
+            recent_triggers,
+            request_range,
+            resubscribe,
+            rotate_identity,
+            self_test,
+            set_bank,
+            set_coalesce_window,
+            set_echo_mode,
+            set_max_peers,
+            set_index_filter,
+            set_publish_rate,
+            set_receive_paused,
+            set_resync_enabled,
+            set_role,
+            set_skew_check,
+            solo_peer,
+            sync_health
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                shutdown(app);
+            }
+        });
+}
+
+/// Stops the node and saves its history, so it is restored on the next start.
+fn shutdown(app: &AppHandle) {
+    // The node might not have started yet.
+    let Some(state) = app.try_state::<AppContext>() else {
+        return;
+    };
+
+    tauri::async_runtime::block_on(state.node.shutdown());
+}
+
+/// Runs the node as a seed peer without any window, logging all events it emits.
+///
+/// Seed peers stay connected to the topic and relay gossip between peers, but never publish
+/// anything themselves, publishing on them is rejected like on listeners. They keep the history
+/// available for peers joining while nobody else is online: everyone joining the room is handed
+/// all triggers in the history via sync, and range requests are answered for any peer. The
+/// history only lives in memory, it is gone when the seed restarts.
+fn run_seed(config: Config) {
+    tauri::async_runtime::block_on(async move {
+        let (node, mut events_rx) = Node::spawn(config, PrivateKey::new(), Room::default()).await;
+        println!(
+            "seed node running with topic {}",
+            hex::encode(node.current_topic().await.id())
+        );
+
+        while let Some(event) = events_rx.recv().await {
+            match serde_json::to_string(&event) {
+                Ok(json) => println!("{json}"),
+                Err(err) => println!("failed to serialize event: {err}"),
+            }
+        }
+    });
 }
 
-fn spawn_node(app: &mut App) -> Result<(), Error> {
+fn spawn_node(app: &mut App, mut config: Config) -> Result<(), Error> {
     let app_handle = app.handle().clone();
 
-    tauri::async_runtime::spawn(async move {
-        let private_key = PrivateKey::new();
-        let network = build_network(private_key.clone())
-            .await
-            .expect("build network");
+    let room_store = match app.path().app_config_dir() {
+        Ok(dir) => Some(RoomStore::new(dir)),
+        Err(err) => {
+            println!("failed to resolve app config directory, rooms aren't remembered: {err}");
+            None
+        }
+    };
 
-        let mut system_events_rx = network
-            .events()
-            .await
-            .expect("subscribe to network system status event stream");
+    match app.path().app_data_dir() {
+        Ok(dir) => config.history_dir = Some(dir),
+        Err(err) => {
+            println!("failed to resolve app data directory, history isn't kept: {err}");
+        }
+    }
 
-        let (topic_tx, mut topic_rx, _topic_ready) = network
-            .subscribe(APP_TOPIC)
-            .await
-            .expect("subscribe to topic");
+    tauri::async_runtime::spawn(async move {
+        let single_window = config.single_window;
+        let room = match room_store {
+            Some(ref room_store) if !config.local_only => room_store.load(),
+            _ => Room::default(),
+        };
+
+        // Startup failures arrive as the first event and are buffered until a window connects.
+        let (node, mut events_rx) = Node::spawn(config, PrivateKey::new(), room).await;
 
         let (channel_init_tx, mut channel_init_rx) = mpsc::channel(32);
-        let (app_tx, mut app_rx) = mpsc::channel(32);
 
-        app_handle.manage(Mutex::new(AppContext {
+        app_handle.manage(AppContext {
             channel_init_tx,
-            network,
-            topic_tx,
-            app_tx,
-        }));
+            node: node.clone(),
+            room_store,
+        });
+
+        let mut frontend = Frontend::new(single_window);
 
-        let mut channel = channel_init_rx
-            .recv()
-            .await
-            .expect("channel arrives on channel init receiver");
+        // The node runs regardless of whether a window ever registers, events are buffered in
+        // the meantime.
+        let wait_warning = sleep(FRONTEND_WAIT_WARNING);
+        tokio::pin!(wait_warning);
+        let mut waiting_for_frontend = true;
 
         loop {
             tokio::select! {
-                Ok(event) = system_events_rx.recv() => {
-                        channel.send(ChannelEvent::SystemEvent(SystemEvent(event))).expect("send on app channel");
-                },
-                Some(event) = topic_rx.recv() => {
-                    let (timestamp, index): (u64, u16) = match event {
-                        FromNetwork::GossipMessage { ref bytes, .. } => {
-                            decode_cbor(&bytes[..]).expect("decode message bytes")
-                        },
-                        // We don't expect to receive any messages via sync.
-                        FromNetwork::SyncMessage { .. } => todo!(),
-                    };
-
-                        channel.send(ChannelEvent::ApplicationMessage(ApplicationMessage {
-                            timestamp,
-                            sample_index: index,
-                            public_key: private_key.public_key()
-                        })).expect("send on app channel");
+                Some(event) = events_rx.recv() => {
+                    frontend.send(event);
                 },
-                Some((timestamp, index)) = app_rx.recv() => {
-                    channel.send(ChannelEvent::ApplicationMessage(ApplicationMessage {
-                        timestamp,
-                        sample_index: index,
-                        public_key: private_key.public_key()
-                    })).expect("send on app channel");
+                _ = &mut wait_warning, if waiting_for_frontend => {
+                    waiting_for_frontend = false;
+                    let message = format!(
+                        "no window registered within {}s of starting, events were buffered",
+                        FRONTEND_WAIT_WARNING.as_secs()
+                    );
+                    println!("warning: {message}");
+                    // Buffered, so the window which registers eventually learns about the delay.
+                    frontend.send(ChannelEvent::Error { message });
                 },
-                Some(new_channel) = channel_init_rx.recv() => {
-                    channel = new_channel
+                Some((new_channel, reply)) = channel_init_rx.recv() => {
+                    waiting_for_frontend = false;
+                    // Give the new window the context it missed before it connected.
+                    let snapshot = match node.snapshot().await {
+                        Ok(snapshot) => Some(snapshot),
+                        Err(err) => {
+                            println!("failed to take snapshot: {err}");
+                            None
+                        }
+                    };
+                    let _ = reply.send(frontend.add_channel(new_channel, snapshot));
                 },
+                else => break,
             }
         }
     });
 
     Ok(())
 }
-
-async fn build_network(private_key: PrivateKey) -> anyhow::Result<Network<AppTopic>> {
-    let mdns = LocalDiscovery::new();
-    let sync_protocol = sync::DummyProtocol {};
-    let resync_config = ResyncConfiguration::new().interval(10);
-    let sync_config = SyncConfiguration::new(sync_protocol).resync(resync_config);
-
-    let network = NetworkBuilder::new(NETWORK_ID)
-        .discovery(mdns)
-        .sync(sync_config)
-        .private_key(private_key.clone())
-        .build()
-        .await?;
-
-    Ok(network)
-}
@@ -1,6 +1,7 @@
-use p2panda_core::PublicKey;
+use p2panda_core::{Hash, PublicKey};
+use p2panda_net::TopicId;
 use serde::ser::SerializeStruct;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::AppTopic;
 
@@ -8,13 +9,203 @@ use crate::AppTopic;
 pub enum ChannelEvent {
     ApplicationMessage(ApplicationMessage),
     SystemEvent(SystemEvent),
+    Reconnecting {
+        attempt: u32,
+    },
+    Reconnected,
+    Error {
+        message: String,
+    },
+    HistoryCleared,
+    PeerCountChanged {
+        count: usize,
+    },
+    /// A failed sync session with a peer is retried, `attempt` counts from 1.
+    SyncRetry {
+        peer: PublicKey,
+        topic_id: [u8; 32],
+        attempt: u32,
+    },
+    /// Syncing with a peer failed after all retries, only the periodic resync tries again.
+    SyncGaveUp {
+        peer: PublicKey,
+        topic_id: [u8; 32],
+        attempts: u32,
+    },
+    SyncProgress {
+        peer: PublicKey,
+        state: SyncState,
+    },
+    /// Progress of a periodic resync with a peer we synced with before, reported in addition to
+    /// `SyncProgress`.
+    Resync {
+        peer: PublicKey,
+        phase: SyncState,
+    },
+    Control {
+        kind: ControlKind,
+    },
+    RawMessage {
+        public_key: PublicKey,
+        bytes: Vec<u8>,
+    },
+    Snapshot {
+        peers: Vec<PublicKey>,
+        recent_triggers: Vec<ApplicationMessage>,
+    },
+    Connected {
+        topic: AppTopic,
+    },
+    PeerDiscovered {
+        peer: PublicKey,
+    },
+    /// No peer was discovered or connected within the discovery timeout.
+    NoPeersFound,
+    /// Events were discarded because a buffer overflowed, counting all dropped since the last
+    /// notice.
+    Dropped {
+        count: u64,
+        reason: DropReason,
+    },
+    /// A peer publishes triggers of a sound bank we don't have, reported once per peer and bank.
+    UnknownBank {
+        peer: PublicKey,
+        bank_id: u16,
+    },
+    /// We joined the gossip overlay of one of the topics we are subscribed to.
+    TopicReady {
+        topic_id: [u8; 32],
+    },
+    /// One of our triggers was handed to the gossip overlay, as opposed to only being echoed
+    /// locally or queued until the topic is ready.
+    Published {
+        timestamp: u64,
+        sample_index: u16,
+    },
+    /// One of our queued triggers couldn't be handed to the gossip overlay.
+    PublishFailed {
+        timestamp: u64,
+        sample_index: u16,
+        reason: String,
+    },
+    /// A neighbour was evicted to stay within the peer cap, we ignore its messages from now on.
+    PeerEvicted {
+        peer: PublicKey,
+    },
+    /// A new neighbour was rejected because the peer cap is reached.
+    PeerRejected {
+        peer: PublicKey,
+    },
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApplicationMessage {
     pub public_key: PublicKey,
     pub timestamp: u64,
     pub sample_index: u16,
+    /// Counts up from 0 for every trigger published by an identity, gaps hint at lost triggers.
+    pub seq: u64,
+    /// Sound bank the sample index refers to.
+    #[serde(default)]
+    pub bank_id: u16,
+    /// Resent from the publisher's history instead of played live, the frontend shouldn't play
+    /// it either.
+    #[serde(default)]
+    pub replay: bool,
+    /// Id of the topic the trigger was published on, serialized as hex.
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub topic_id: [u8; 32],
+    /// Color hue of the sender from 0 to 359, see `peer_hue`.
+    pub hue: u16,
+}
+
+/// Stable color hue from 0 to 359 for a peer, derived from its public key so every client shows
+/// the same peer in the same color.
+pub fn peer_hue(public_key: &PublicKey) -> u16 {
+    let hash = Hash::new(public_key.as_bytes());
+    let bytes = hash.as_bytes();
+    u16::from_be_bytes([bytes[0], bytes[1]]) % 360
+}
+
+fn serialize_hex<S: serde::Serializer>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+fn deserialize_hex<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u8; 32], D::Error> {
+    let bytes =
+        hex::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)?;
+    bytes
+        .try_into()
+        .map_err(|_| serde::de::Error::custom("expected 32 bytes"))
+}
+
+/// Control messages which act on the whole session rather than a single sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlKind {
+    /// Silence everything now, the equivalent of a MIDI panic.
+    AllNotesOff,
+}
+
+/// How a published message is delivered to other peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Delivery {
+    /// Broadcast immediately to all neighbours, for live triggers.
+    #[default]
+    Gossip,
+    /// Handed out during the sync sessions with every peer for the next two minutes, for bulk
+    /// data which shouldn't flood the gossip overlay. Arrives with a delay of up to one resync
+    /// interval.
+    Sync,
+}
+
+/// Buffer which overflowed when events were dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DropReason {
+    /// Events buffered while no frontend window was registered.
+    NoFrontend,
+    /// Events of a stream which fell behind.
+    StreamLagged,
+    /// Events for the receiver returned by `Node::spawn`, which wasn't drained fast enough.
+    ReceiverFull,
+    /// Incoming triggers held back while receiving was paused.
+    ReceivePaused,
+}
+
+/// When our own published messages are echoed back to the frontend.
+///
+/// Immediate echoes keep latency minimal but show messages which might never reach anyone, for
+/// example while the topic isn't ready and they are still queued. Confirmed echoes only show
+/// messages once they were handed to the gossip overlay, at the cost of the delay that takes.
+/// Either way every message is echoed exactly once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum EchoMode {
+    /// Echo right away when publishing.
+    #[default]
+    Immediate,
+    /// Echo once handed to the gossip overlay. Messages delivered via sync, and all messages of
+    /// local-only nodes, are still echoed right away as nothing ever confirms them.
+    Confirmed,
+}
+
+/// Whether we contribute triggers or only receive them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// Publishes and receives triggers.
+    #[default]
+    Performer,
+    /// Only receives triggers, for example an audience member or a projection display. All
+    /// publishing is rejected, syncing history still works and heartbeats announce us as a
+    /// listener.
+    Listener,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum SyncState {
+    Started,
+    Done,
+    Failed,
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +229,172 @@ impl Serialize for ChannelEvent {
                 state.serialize_field("data", event)?;
                 state.end()
             }
+            ChannelEvent::Reconnecting { attempt } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "Reconnecting")?;
+                state.serialize_field("attempt", &attempt)?;
+                state.end()
+            }
+            ChannelEvent::Reconnected => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 1)?;
+                state.serialize_field("type", "Reconnected")?;
+                state.end()
+            }
+            ChannelEvent::HistoryCleared => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 1)?;
+                state.serialize_field("type", "HistoryCleared")?;
+                state.end()
+            }
+            ChannelEvent::PeerCountChanged { count } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "PeerCountChanged")?;
+                state.serialize_field("count", &count)?;
+                state.end()
+            }
+            ChannelEvent::SyncRetry {
+                ref peer,
+                topic_id,
+                attempt,
+            } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 4)?;
+                state.serialize_field("type", "SyncRetry")?;
+                state.serialize_field("peer", peer)?;
+                state.serialize_field("topic_id", &hex::encode(topic_id))?;
+                state.serialize_field("attempt", &attempt)?;
+                state.end()
+            }
+            ChannelEvent::SyncGaveUp {
+                ref peer,
+                topic_id,
+                attempts,
+            } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 4)?;
+                state.serialize_field("type", "SyncGaveUp")?;
+                state.serialize_field("peer", peer)?;
+                state.serialize_field("topic_id", &hex::encode(topic_id))?;
+                state.serialize_field("attempts", &attempts)?;
+                state.end()
+            }
+            ChannelEvent::SyncProgress {
+                ref peer,
+                state: sync_state,
+            } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 3)?;
+                state.serialize_field("type", "SyncProgress")?;
+                state.serialize_field("peer", peer)?;
+                state.serialize_field("state", &sync_state)?;
+                state.end()
+            }
+            ChannelEvent::Resync { ref peer, phase } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 3)?;
+                state.serialize_field("type", "Resync")?;
+                state.serialize_field("peer", peer)?;
+                state.serialize_field("phase", &phase)?;
+                state.end()
+            }
+            ChannelEvent::Control { kind } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "Control")?;
+                state.serialize_field("kind", &kind)?;
+                state.end()
+            }
+            ChannelEvent::RawMessage {
+                ref public_key,
+                ref bytes,
+            } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 3)?;
+                state.serialize_field("type", "RawMessage")?;
+                state.serialize_field("public_key", public_key)?;
+                state.serialize_field("bytes", bytes)?;
+                state.end()
+            }
+            ChannelEvent::Snapshot {
+                ref peers,
+                ref recent_triggers,
+            } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 3)?;
+                state.serialize_field("type", "Snapshot")?;
+                state.serialize_field("peers", peers)?;
+                state.serialize_field("recent_triggers", recent_triggers)?;
+                state.end()
+            }
+            ChannelEvent::Connected { topic } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "Connected")?;
+                state.serialize_field("topic", &hex::encode(topic.id()))?;
+                state.end()
+            }
+            ChannelEvent::PeerDiscovered { ref peer } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "PeerDiscovered")?;
+                state.serialize_field("peer", peer)?;
+                state.end()
+            }
+            ChannelEvent::TopicReady { topic_id } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "TopicReady")?;
+                state.serialize_field("topic_id", &hex::encode(topic_id))?;
+                state.end()
+            }
+            ChannelEvent::UnknownBank { ref peer, bank_id } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 3)?;
+                state.serialize_field("type", "UnknownBank")?;
+                state.serialize_field("peer", peer)?;
+                state.serialize_field("bank_id", &bank_id)?;
+                state.end()
+            }
+            ChannelEvent::Dropped { count, reason } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 3)?;
+                state.serialize_field("type", "Dropped")?;
+                state.serialize_field("count", &count)?;
+                state.serialize_field("reason", &reason)?;
+                state.end()
+            }
+            ChannelEvent::NoPeersFound => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 1)?;
+                state.serialize_field("type", "NoPeersFound")?;
+                state.end()
+            }
+            ChannelEvent::Published {
+                timestamp,
+                sample_index,
+            } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 3)?;
+                state.serialize_field("type", "Published")?;
+                state.serialize_field("timestamp", &timestamp)?;
+                state.serialize_field("sample_index", &sample_index)?;
+                state.end()
+            }
+            ChannelEvent::PublishFailed {
+                timestamp,
+                sample_index,
+                ref reason,
+            } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 4)?;
+                state.serialize_field("type", "PublishFailed")?;
+                state.serialize_field("timestamp", &timestamp)?;
+                state.serialize_field("sample_index", &sample_index)?;
+                state.serialize_field("reason", reason)?;
+                state.end()
+            }
+            ChannelEvent::PeerEvicted { ref peer } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "PeerEvicted")?;
+                state.serialize_field("peer", peer)?;
+                state.end()
+            }
+            ChannelEvent::PeerRejected { ref peer } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "PeerRejected")?;
+                state.serialize_field("peer", peer)?;
+                state.end()
+            }
+            ChannelEvent::Error { ref message } => {
+                let mut state = serializer.serialize_struct("ChannelEvent", 2)?;
+                state.serialize_field("type", "Error")?;
+                state.serialize_field("message", message)?;
+                state.end()
+            }
         }
     }
 }
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::clock::now_millis;
+
+/// Number of seconds message rates are averaged over.
+pub const RATE_WINDOW_SECS: u64 = 5;
+
+/// Messages per second published and received, averaged over the last few seconds.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct MessageRate {
+    pub sent_per_sec: f64,
+    pub received_per_sec: f64,
+}
+
+/// How full the queues between the frontend, the node task and the network are.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct QueueDepth {
+    /// Messages waiting to be echoed by the node task.
+    pub app_queued: usize,
+    pub app_capacity: usize,
+    /// Messages waiting to be handed to the gossip overlay.
+    pub topic_queued: usize,
+    pub topic_capacity: usize,
+}
+
+impl QueueDepth {
+    /// Number of messages which can be published before running into backpressure.
+    pub fn remaining(&self) -> usize {
+        (self.app_capacity - self.app_queued).min(self.topic_capacity - self.topic_queued)
+    }
+}
+
+/// Counts messages in one bucket per second, covering the rolling window.
+#[derive(Debug, Default)]
+struct RollingCounter {
+    /// Second since the UNIX epoch and the number of messages counted in it.
+    buckets: [(u64, u64); RATE_WINDOW_SECS as usize],
+}
+
+impl RollingCounter {
+    fn record(&mut self, now_secs: u64) {
+        let bucket = &mut self.buckets[(now_secs % RATE_WINDOW_SECS) as usize];
+        if bucket.0 != now_secs {
+            *bucket = (now_secs, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    fn per_second(&self, now_secs: u64) -> f64 {
+        let count: u64 = self
+            .buckets
+            .iter()
+            .filter(|(second, _)| now_secs.saturating_sub(*second) < RATE_WINDOW_SECS)
+            .map(|(_, count)| count)
+            .sum();
+        count as f64 / RATE_WINDOW_SECS as f64
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: RollingCounter,
+    received: RollingCounter,
+}
+
+/// Rolling rate of published and received messages, shared between node handle and task.
+///
+/// Recording only holds a lock for a few instructions, so it is cheap enough for every message.
+#[derive(Clone, Debug, Default)]
+pub struct MessageRates {
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl MessageRates {
+    pub fn record_sent(&self) {
+        let now_secs = now_millis() / 1000;
+        self.counters
+            .lock()
+            .expect("lock message rates")
+            .sent
+            .record(now_secs);
+    }
+
+    pub fn record_received(&self) {
+        let now_secs = now_millis() / 1000;
+        self.counters
+            .lock()
+            .expect("lock message rates")
+            .received
+            .record(now_secs);
+    }
+
+    pub fn rate(&self) -> MessageRate {
+        let now_secs = now_millis() / 1000;
+        let counters = self.counters.lock().expect("lock message rates");
+        MessageRate {
+            sent_per_sec: counters.sent.per_second(now_secs),
+            received_per_sec: counters.received.per_second(now_secs),
+        }
+    }
+}
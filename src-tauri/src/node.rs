@@ -0,0 +1,2405 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures_util::stream::{self, Stream};
+use p2panda_core::{PrivateKey, PublicKey};
+use p2panda_discovery::mdns::LocalDiscovery;
+use p2panda_net::{FromNetwork, Network, NetworkBuilder, ResyncConfiguration, ToNetwork, TopicId};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tokio::time::{interval, sleep, sleep_until, timeout, Instant, Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use crate::clock::{now_millis, SkewCheck, SkewDecision, DEFAULT_MAX_SKEW};
+use crate::coalesce::Coalesce;
+use crate::config::Config;
+use crate::dedup::Dedup;
+use crate::diagnostics::{
+    Diagnostics, SelfTestReport, SelfTestStage, StageReport, DISCOVERY_WINDOW,
+};
+use crate::discovery::network_interfaces;
+use crate::encryption::{self, RoomKey};
+use crate::history::{History, HistoryStore, StoredTrigger};
+use crate::messages::{
+    peer_hue, ApplicationMessage, ChannelEvent, ControlKind, Delivery, DropReason, EchoMode, Role,
+    SyncState, SystemEvent,
+};
+use crate::metrics::{MessageRate, MessageRates, QueueDepth};
+use crate::peers::{PeerChange, PeerQuality, Peers, Presence};
+use crate::resync::Resyncs;
+use crate::retry::{RetryProgress, SyncRetries};
+use crate::room::{Lobby, Room, RoomInfo, LOBBY_TOPIC, ROOM_ADVERTISE_INTERVAL};
+use crate::state::AppStateSnapshot;
+use crate::sync::{ResyncSwitch, SyncFailure, SyncOutbox};
+use crate::sync_health::{PeerSyncHealth, SyncHealth};
+use crate::throttle::RateLimiter;
+use crate::wire::{decode_payload, encode_envelope, encode_for_room, encode_payload, Payload};
+use crate::AppTopic;
+
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Maximum number of messages held back while the topic is not ready yet, oldest are dropped.
+const MAX_PENDING_MESSAGES: usize = 256;
+
+/// Maximum number of triggers included in a snapshot, the most recent ones are picked.
+const SNAPSHOT_MAX_TRIGGERS: usize = 50;
+
+/// Number of events buffered for each `Node::events` stream, slow consumers skip older ones.
+const EVENTS_BROADCAST_CAPACITY: usize = 256;
+
+/// Number of events the receiver returned by `Node::spawn` holds, newer ones are dropped once
+/// it is full.
+const EVENTS_CHANNEL_CAPACITY: usize = 256;
+
+/// How long shutting down waits for the network before giving up on it.
+const NETWORK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `Node::shutdown` waits for the node task, which might be busy with a command or
+/// building a network, on top of shutting the network down.
+const NODE_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of incoming triggers held back while receiving is paused, the oldest are
+/// dropped.
+const MAX_PAUSED_MESSAGES: usize = 256;
+
+/// How long the self test waits for its message to be echoed.
+const SELF_TEST_ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reasons why a message couldn't be published.
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error("publishing too fast, message rejected")]
+    RateLimited,
+    #[error("node can't keep up, message rejected")]
+    Backpressure,
+    #[error("message of {size} bytes exceeds the size limit of {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+    #[error("timestamp {timestamp} is more than {max_skew:?} away from the local clock")]
+    TimestampOutOfRange { timestamp: u64, max_skew: Duration },
+    #[error("listeners and seed nodes never publish, message rejected")]
+    ReadOnly,
+    #[error("node stopped, message rejected")]
+    NodeStopped,
+}
+
+/// The node task stopped, after `Node::shutdown` or because it failed, and can't handle commands
+/// anymore.
+#[derive(Debug, Error)]
+#[error("node stopped")]
+pub struct NodeStopped;
+
+/// State shared between the node handle and the node task.
+struct Context {
+    #[allow(dead_code)]
+    network: Option<Network<AppTopic>>,
+    private_key: PrivateKey,
+    topic: AppTopic,
+    topic_tx: mpsc::Sender<ToNetwork>,
+    lobby_tx: mpsc::Sender<ToNetwork>,
+    /// Whether we joined the gossip overlay of each topic we are subscribed to, by topic id.
+    topics_ready: HashMap<[u8; 32], bool>,
+    pending_messages: VecDeque<PendingMessage>,
+    /// Echoes queued messages once they were published, in confirmed echo mode.
+    app_tx: mpsc::Sender<Payload>,
+    echo_mode: EchoMode,
+    role: Role,
+    sync_outbox: SyncOutbox,
+    rate_limiter: RateLimiter,
+    /// Sequence number of the next trigger we publish.
+    next_seq: u64,
+    /// Explicit timestamps of published triggers may not deviate further from our clock.
+    max_skew: Duration,
+    /// Key of the room we are in, if its messages are encrypted.
+    room_key: Option<RoomKey>,
+    /// Sound bank our triggers refer to.
+    bank_id: u16,
+}
+
+impl Context {
+    /// Whether messages on the room's topic reach the gossip overlay right away.
+    fn topic_ready(&self) -> bool {
+        self.topics_ready.get(&self.topic.id()) == Some(&true)
+    }
+
+    /// Marks all topics as not ready, for example after subscribing anew.
+    fn reset_readiness(&mut self) {
+        self.topics_ready = HashMap::from([(self.topic.id(), false), (LOBBY_TOPIC.id(), false)]);
+    }
+}
+
+/// Message held back until the topic is ready.
+struct PendingMessage {
+    payload: Payload,
+    /// Whether the message is echoed once published, as it wasn't when it was queued.
+    echo: bool,
+}
+
+/// Requests handled by the node task as they need to replace parts of the running network.
+enum NodeCommand {
+    RotateIdentity {
+        reply: oneshot::Sender<anyhow::Result<PublicKey>>,
+    },
+    JoinRoom {
+        room: Room,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    ClearHistory {
+        reply: oneshot::Sender<()>,
+    },
+    PeerQuality {
+        reply: oneshot::Sender<Vec<PeerQuality>>,
+    },
+    Snapshot {
+        reply: oneshot::Sender<ChannelEvent>,
+    },
+    DiscoveredPeers {
+        reply: oneshot::Sender<Vec<PublicKey>>,
+    },
+    Presence {
+        reply: oneshot::Sender<Vec<Presence>>,
+    },
+    DiscoverRooms {
+        reply: oneshot::Sender<Vec<RoomInfo>>,
+    },
+    Diagnostics {
+        reply: oneshot::Sender<Diagnostics>,
+    },
+    /// Reports the connection stages of the self test and registers the heartbeat it publishes,
+    /// the returned receiver resolves once that heartbeat is echoed.
+    SelfTest {
+        timestamp: u64,
+        reply: oneshot::Sender<(Vec<StageReport>, oneshot::Receiver<()>)>,
+    },
+    SyncHealth {
+        reply: oneshot::Sender<Vec<PeerSyncHealth>>,
+    },
+    PushHistory {
+        peer: PublicKey,
+        reply: oneshot::Sender<anyhow::Result<usize>>,
+    },
+    Resubscribe {
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    FullState {
+        queue_depth: QueueDepth,
+        reply: oneshot::Sender<AppStateSnapshot>,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+    SetIndexFilter(Option<HashSet<u16>>),
+    SetSolo(Option<PublicKey>),
+    SetKnownBanks(HashSet<u16>),
+    SetReceivePaused {
+        paused: bool,
+        flush_on_resume: bool,
+    },
+    SetSkewCheck(SkewCheck),
+    SetCoalesceWindow(Option<Duration>),
+    SetMaxPeers(Option<usize>),
+}
+
+/// Handle to a running node.
+///
+/// The node joins the application topic, keeps reconnecting when the network drops and reports
+/// everything that happens as `ChannelEvent`s. It is independent of Tauri, so it can run
+/// headless, for example as an always-on peer.
+#[derive(Clone)]
+pub struct Node {
+    context: Arc<Mutex<Context>>,
+    app_tx: mpsc::Sender<Payload>,
+    command_tx: mpsc::Sender<NodeCommand>,
+    history: Arc<Mutex<History>>,
+    message_rates: MessageRates,
+    seed: bool,
+    local_only: bool,
+    max_payload_size: usize,
+    resync: ResyncSwitch,
+    events_tx: EventSender,
+}
+
+impl Node {
+    /// Builds the network, subscribes to the room's topic and spawns the node task.
+    ///
+    /// Returns the node handle and the stream of events emitted by the node. The node never waits
+    /// for the receiver, while it is full events are dropped and a `Dropped` event follows once
+    /// there is room again. When the network can't be built, the failure is reported as the first
+    /// `ChannelEvent::Error` and the node keeps trying to connect, just like after losing the
+    /// connection.
+    pub async fn spawn(
+        config: Config,
+        private_key: PrivateKey,
+        room: Room,
+    ) -> (Self, mpsc::Receiver<ChannelEvent>) {
+        let (sync_failures_tx, sync_failures_rx) = mpsc::channel(32);
+        let sync_outbox = SyncOutbox::default();
+        let resync = ResyncSwitch::default();
+        let (events_tx, events_rx) = mpsc::channel(EVENTS_CHANNEL_CAPACITY);
+        let (events_broadcast_tx, _) = broadcast::channel(EVENTS_BROADCAST_CAPACITY);
+        let events_tx = EventSender {
+            tx: events_tx,
+            broadcast_tx: events_broadcast_tx,
+            dropped: Arc::default(),
+        };
+
+        let (mut connection, topic_ready, connect_error) = match connect(
+            &config,
+            &room,
+            private_key.clone(),
+            sync_failures_tx.clone(),
+            sync_outbox.clone(),
+            resync.clone(),
+        )
+        .await
+        {
+            Ok((connection, topic_ready)) => (connection, topic_ready, None),
+            Err(err) => {
+                let message = format!("failed to start network: {err:#}");
+                println!("{message}");
+                events_tx.send(ChannelEvent::Error { message }).await;
+
+                // The topic never becomes ready, the node task reconnects right away.
+                let (_, topic_ready) = oneshot::channel();
+                (Connection::closed(), topic_ready, Some(format!("{err:#}")))
+            }
+        };
+
+        let (app_tx, app_rx) = mpsc::channel(32);
+        let (command_tx, command_rx) = mpsc::channel(32);
+
+        let history_store = config.history_dir.clone().map(HistoryStore::new);
+        let mut history = History::new(config.history_capacity);
+        if let Some(ref history_store) = history_store {
+            // Triggers published in another room than the one we are in are skipped.
+            for trigger in history_store.load() {
+                if trigger.message.topic_id == room.topic.id() {
+                    history.push(trigger);
+                }
+            }
+        }
+        let history = Arc::new(Mutex::new(history));
+        let message_rates = MessageRates::default();
+
+        let mut context = Context {
+            network: connection.network.clone(),
+            private_key: private_key.clone(),
+            topic: room.topic,
+            topic_tx: connection.topic_tx.clone(),
+            lobby_tx: connection.lobby_tx.clone(),
+            topics_ready: HashMap::new(),
+            pending_messages: VecDeque::new(),
+            app_tx: app_tx.clone(),
+            echo_mode: EchoMode::default(),
+            role: if config.listener {
+                Role::Listener
+            } else {
+                Role::Performer
+            },
+            sync_outbox: sync_outbox.clone(),
+            rate_limiter: RateLimiter::default(),
+            next_seq: 0,
+            max_skew: DEFAULT_MAX_SKEW,
+            room_key: room.encryption_key.clone(),
+            bank_id: 0,
+        };
+        context.reset_readiness();
+        let context = Arc::new(Mutex::new(context));
+        flush_when_ready(
+            context.clone(),
+            connection.topic_tx.clone(),
+            topic_ready,
+            events_tx.clone(),
+        );
+        if let Some(lobby_ready) = connection.lobby_ready.take() {
+            mark_lobby_when_ready(
+                context.clone(),
+                connection.lobby_tx.clone(),
+                lobby_ready,
+                events_tx.clone(),
+            );
+        }
+
+        let seed = config.seed;
+        let local_only = config.local_only;
+        let max_payload_size = config.max_payload_size;
+        let heartbeat = config.heartbeat_interval.map(|period| {
+            let mut heartbeat = interval(period);
+            heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            heartbeat
+        });
+        let task = NodeTask {
+            config,
+            private_key,
+            room,
+            context: context.clone(),
+            connection,
+            connect_error,
+            events_tx: events_tx.clone(),
+            app_rx,
+            command_rx,
+            sync_failures_tx,
+            sync_failures_rx,
+            sync_outbox,
+            resync: resync.clone(),
+            history: history.clone(),
+            history_store,
+            message_rates: message_rates.clone(),
+            index_filter: None,
+            solo: None,
+            known_banks: HashSet::from([0]),
+            unknown_banks: HashSet::new(),
+            paused: None,
+            skew_check: SkewCheck::default(),
+            coalesce: Coalesce::default(),
+            heartbeat,
+            room_advertisement: interval(ROOM_ADVERTISE_INTERVAL),
+            lobby: Lobby::default(),
+            // Peers are never found without a network.
+            peers: Peers::new(
+                config.max_peers,
+                config.discovery_timeout.filter(|_| !config.local_only),
+            ),
+            sync_retries: SyncRetries::default(),
+            resyncs: Resyncs::default(),
+            sync_health: SyncHealth::default(),
+            dedup: Dedup::default(),
+            self_test_echo: None,
+            stopped: false,
+        };
+        tokio::spawn(task.run());
+
+        let node = Self {
+            context,
+            app_tx,
+            command_tx,
+            history,
+            message_rates,
+            seed,
+            local_only,
+            max_payload_size,
+            resync,
+            events_tx,
+        };
+
+        (node, events_rx)
+    }
+
+    /// Stream of all events emitted by the node from now on, the same ones which arrive on the
+    /// receiver returned by `spawn`.
+    ///
+    /// Every call returns an independent stream. Streams which fall behind by more than 256
+    /// events skip the oldest ones, a `Dropped` event tells how many were skipped.
+    pub fn events(&self) -> impl Stream<Item = ChannelEvent> {
+        stream::unfold(
+            self.events_tx.broadcast_tx.subscribe(),
+            |mut rx| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(event) => return Some((event, rx)),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            println!("event stream lagged, skipped {skipped} events");
+                            let event = ChannelEvent::Dropped {
+                                count: skipped,
+                                reason: DropReason::StreamLagged,
+                            };
+                            return Some((event, rx));
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )
+    }
+
+    /// Publishes a trigger on the topic and echoes it locally.
+    ///
+    /// Without a timestamp the trigger is stamped with the current time. Explicit timestamps are
+    /// rejected when they are further away from our clock than peers would accept.
+    ///
+    /// Seed nodes and listeners reject this and every other publish with
+    /// `PublishError::ReadOnly`.
+    pub async fn publish(
+        &self,
+        timestamp: Option<u64>,
+        index: u16,
+        delivery: Delivery,
+    ) -> Result<usize, PublishError> {
+        let timestamp = match timestamp {
+            Some(timestamp) => {
+                let max_skew = self.context.lock().await.max_skew;
+                if now_millis().abs_diff(timestamp) > max_skew.as_millis() as u64 {
+                    return Err(PublishError::TimestampOutOfRange {
+                        timestamp,
+                        max_skew,
+                    });
+                }
+                timestamp
+            }
+            None => now_millis(),
+        };
+
+        let payload = Payload::Trigger {
+            timestamp,
+            sample_index: index,
+            seq: 0,
+            bank_id: 0,
+        };
+        self.publish_payload(payload, delivery).await
+    }
+
+    /// Publishes a control message telling everyone to silence all sounds immediately.
+    ///
+    /// Control messages are never rate limited.
+    pub async fn all_notes_off(&self) {
+        let _ = self
+            .publish_payload(Payload::Control(ControlKind::AllNotesOff), Delivery::Gossip)
+            .await;
+    }
+
+    /// Publishes arbitrary bytes on the topic and echoes them locally.
+    ///
+    /// Raw payloads are signed like all other messages but otherwise bypass the trigger handling,
+    /// they are neither kept in the history nor checked for clock skew.
+    pub async fn publish_bytes(
+        &self,
+        bytes: Vec<u8>,
+        delivery: Delivery,
+    ) -> Result<usize, PublishError> {
+        self.publish_payload(Payload::Raw(bytes), delivery).await
+    }
+
+    /// Asks a peer to resend its triggers with sequence numbers from `from` to `to`.
+    ///
+    /// The peer, and any seed which still has them, hands them out during the next sync sessions,
+    /// they are forwarded like any other trigger. If the range was evicted from the peer's
+    /// history an `Error` event follows instead.
+    ///
+    /// Listeners and seeds can request ranges as well, and requests aren't rate limited.
+    pub async fn request_range(
+        &self,
+        peer: PublicKey,
+        from: u64,
+        to: u64,
+    ) -> Result<(), PublishError> {
+        self.publish_payload(Payload::RangeRequest { peer, from, to }, Delivery::Gossip)
+            .await
+            .map(|_| ())
+    }
+
+    /// Pauses or resumes pulling in messages from peers during sync sessions.
+    ///
+    /// Takes effect with the next session, the network isn't rebuilt so gossip stays connected.
+    /// While paused, sessions we initiate only complete the handshake. Sessions peers initiate
+    /// still hand out our outbox so they can catch up with us, but everything they send us is
+    /// skipped. We don't catch up with anyone in the meantime, including peers joining after the
+    /// pause.
+    pub fn set_resync_enabled(&self, enabled: bool) {
+        self.resync.set(enabled);
+    }
+
+    /// Configures how many messages per second can be published, and how many in a quick burst.
+    pub async fn set_publish_rate(&self, rate: f64, burst: u32) {
+        self.context
+            .lock()
+            .await
+            .rate_limiter
+            .configure(rate, burst);
+    }
+
+    /// Returns how many more messages can be published before running into backpressure.
+    ///
+    /// Messages are rejected instead of waiting when one of the queues on their way is full.
+    async fn publish_payload(
+        &self,
+        mut message: Payload,
+        delivery: Delivery,
+    ) -> Result<usize, PublishError> {
+        // Asking for missed triggers doesn't publish anything of our own, so listeners and seeds
+        // may do it as well and it doesn't count against the publish rate.
+        let range_request = matches!(message, Payload::RangeRequest { .. });
+
+        if self.seed && !range_request {
+            println!("seed nodes don't publish, message rejected: {:?}", message);
+            return Err(PublishError::ReadOnly);
+        }
+
+        let mut context = self.context.lock().await;
+
+        if context.role == Role::Listener && !range_request {
+            println!("listeners don't publish, message rejected: {:?}", message);
+            return Err(PublishError::ReadOnly);
+        }
+
+        // Peers would drop the message anyway.
+        let size = encode_envelope(&context.private_key, message.clone()).len();
+        if size > self.max_payload_size {
+            println!("message of {size} bytes too large, message rejected");
+            return Err(PublishError::TooLarge {
+                size,
+                limit: self.max_payload_size,
+            });
+        }
+
+        // Reserve room in all queues before taking a rate limiter token, so a rejected message
+        // leaves no trace. Unused permits are released when they are dropped.
+        let app_permit = match self.app_tx.try_reserve() {
+            Ok(permit) => permit,
+            Err(mpsc::error::TrySendError::Full(())) => return Err(PublishError::Backpressure),
+            Err(mpsc::error::TrySendError::Closed(())) => return Err(PublishError::NodeStopped),
+        };
+        let topic_tx = context.topic_tx.clone();
+        let gossip = !self.local_only && delivery == Delivery::Gossip && context.topic_ready();
+        let topic_permit = if gossip {
+            match topic_tx.try_reserve() {
+                Ok(permit) => Some(permit),
+                Err(mpsc::error::TrySendError::Full(())) => return Err(PublishError::Backpressure),
+                // The network is being rebuilt, the message is queued until the new topic is
+                // ready.
+                Err(mpsc::error::TrySendError::Closed(())) => None,
+            }
+        } else {
+            None
+        };
+
+        // Taken last, so the token is only used up once nothing else rejects the message. Control
+        // messages like all notes off must always get through.
+        let limited = !matches!(message, Payload::Control(_)) && !range_request;
+        if limited && !context.rate_limiter.try_acquire() {
+            println!("publish rate exceeded, message rejected: {:?}", message);
+            return Err(PublishError::RateLimited);
+        }
+
+        self.message_rates.record_sent();
+
+        if let Payload::Trigger {
+            ref mut seq,
+            ref mut bank_id,
+            ..
+        } = message
+        {
+            *seq = context.next_seq;
+            context.next_seq += 1;
+            *bank_id = context.bank_id;
+        }
+
+        // In confirmed mode, gossiped messages are only echoed once they were handed to the gossip
+        // overlay, the reserved permit is kept until then.
+        let confirm = context.echo_mode == EchoMode::Confirmed
+            && !self.local_only
+            && delivery == Delivery::Gossip;
+        let app_permit = if confirm {
+            Some(app_permit)
+        } else {
+            app_permit.send(message.clone());
+            None
+        };
+
+        // Nothing ever reaches the network, only the echo can back up.
+        if self.local_only {
+            return Ok(self.app_tx.capacity());
+        }
+
+        // Sync sessions don't depend on the gossip overlay, the message goes out with the next
+        // one regardless of the topic being ready.
+        if delivery == Delivery::Sync {
+            let bytes = encode_for_room(
+                &context.private_key,
+                context.room_key.as_ref(),
+                message.clone(),
+            );
+            context.sync_outbox.push(bytes).await;
+            println!("message queued for sync: {:?}", message);
+            return Ok(self.queue_depth_of(&topic_tx).remaining());
+        }
+
+        // Messages sent before we joined the gossip overlay would be lost, so hold them back
+        // until the topic is ready.
+        let Some(topic_permit) = topic_permit else {
+            if context.pending_messages.len() == MAX_PENDING_MESSAGES {
+                context.pending_messages.pop_front();
+            }
+            println!("topic not ready, message queued: {:?}", message);
+            context.pending_messages.push_back(PendingMessage {
+                payload: message,
+                echo: app_permit.is_some(),
+            });
+            return Ok(self.queue_depth_of(&topic_tx).remaining());
+        };
+
+        let bytes = encode_for_room(
+            &context.private_key,
+            context.room_key.as_ref(),
+            message.clone(),
+        );
+        topic_permit.send(ToNetwork::Message { bytes });
+        drop(context);
+        if let Some(app_permit) = app_permit {
+            app_permit.send(message.clone());
+        }
+
+        println!("message published: {:?}", message);
+        if let Some(event) = published_event(&message) {
+            self.events_tx.send(event).await;
+        }
+        Ok(self.queue_depth_of(&topic_tx).remaining())
+    }
+
+    /// How full the queues published messages pass through are.
+    pub async fn queue_depth(&self) -> QueueDepth {
+        let topic_tx = self.context.lock().await.topic_tx.clone();
+        self.queue_depth_of(&topic_tx)
+    }
+
+    fn queue_depth_of(&self, topic_tx: &mpsc::Sender<ToNetwork>) -> QueueDepth {
+        QueueDepth {
+            app_queued: self.app_tx.max_capacity() - self.app_tx.capacity(),
+            app_capacity: self.app_tx.max_capacity(),
+            topic_queued: topic_tx.max_capacity() - topic_tx.capacity(),
+            topic_capacity: topic_tx.max_capacity(),
+        }
+    }
+
+    /// Topic the node is subscribed to.
+    pub async fn current_topic(&self) -> AppTopic {
+        self.context.lock().await.topic
+    }
+
+    /// Most recently published and received triggers, oldest first.
+    pub async fn recent_triggers(&self) -> Vec<ApplicationMessage> {
+        self.history.lock().await.to_messages()
+    }
+
+    /// Saves the history if `Config::history_dir` is set, stops the node task and shuts the
+    /// network down, which announces our departure to peers.
+    ///
+    /// Waits at most 10 seconds, so exiting doesn't hang when the network or the node task is
+    /// wedged. The history stays readable afterwards, everything else of the node must not be
+    /// used anymore.
+    pub async fn shutdown(&self) {
+        let (reply, reply_rx) = oneshot::channel();
+        if self
+            .send_command(NodeCommand::Shutdown { reply })
+            .await
+            .is_err()
+        {
+            // Stopped already.
+            return;
+        }
+        if timeout(NODE_SHUTDOWN_TIMEOUT, reply_rx).await.is_err() {
+            println!("node didn't shut down within {NODE_SHUTDOWN_TIMEOUT:?}");
+        }
+    }
+
+    /// Removes all triggers from the local history, including the saved one.
+    ///
+    /// Clearing is handled by the node task, so triggers arriving concurrently are either
+    /// cleared as well or land in the emptied history afterwards, but never get lost in between.
+    pub async fn clear_history(&self) -> Result<(), NodeStopped> {
+        self.request(|reply| NodeCommand::ClearHistory { reply })
+            .await
+    }
+
+    /// Messages per second published and received over the last few seconds.
+    pub fn message_rate(&self) -> MessageRate {
+        self.message_rates.rate()
+    }
+
+    /// Connection quality of all gossip neighbours.
+    pub async fn peer_quality(&self) -> Result<Vec<PeerQuality>, NodeStopped> {
+        self.request(|reply| NodeCommand::PeerQuality { reply })
+            .await
+    }
+
+    /// When we last heard of every peer who published something, heartbeats included.
+    pub async fn presence(&self) -> Result<Vec<Presence>, NodeStopped> {
+        self.request(|reply| NodeCommand::Presence { reply }).await
+    }
+
+    /// How reliably syncing with each peer works, with counts and the most recent outcomes.
+    pub async fn sync_health(&self) -> Result<Vec<PeerSyncHealth>, NodeStopped> {
+        self.request(|reply| NodeCommand::SyncHealth { reply })
+            .await
+    }
+
+    /// Everything the node currently knows in one go: identity, topic, neighbours, recent
+    /// triggers and traffic stats.
+    pub async fn full_state(&self) -> Result<AppStateSnapshot, NodeStopped> {
+        let queue_depth = self.queue_depth().await;
+        self.request(|reply| NodeCommand::FullState { queue_depth, reply })
+            .await
+    }
+
+    /// Checks whether the node itself works, apart from finding peers.
+    ///
+    /// Reports whether the network was built and subscribed to our topic, whether the topic is
+    /// ready and whether a published message is echoed back. The message is a heartbeat, so
+    /// peers don't play anything.
+    pub async fn self_test(&self) -> Result<SelfTestReport, NodeStopped> {
+        let timestamp = now_millis();
+        let (mut stages, echo_rx) = self
+            .request(|reply| NodeCommand::SelfTest { timestamp, reply })
+            .await?;
+
+        let stage = SelfTestStage::PublishRoundTrip;
+        let round_trip = if self.seed {
+            StageReport::skipped(stage, "seed nodes never publish")
+        } else if self.role().await == Role::Listener {
+            StageReport::skipped(stage, "listeners never publish")
+        } else {
+            let heartbeat = Payload::Heartbeat {
+                timestamp,
+                listener: false,
+            };
+            match self.publish_payload(heartbeat, Delivery::Gossip).await {
+                Ok(_) => match timeout(SELF_TEST_ECHO_TIMEOUT, echo_rx).await {
+                    Ok(Ok(())) => StageReport::passed(stage),
+                    _ => StageReport::failed(stage, "published message wasn't echoed in time"),
+                },
+                Err(err) => StageReport::failed(stage, format!("publishing failed: {err}")),
+            }
+        };
+        stages.push(round_trip);
+
+        Ok(SelfTestReport::new(stages))
+    }
+
+    pub async fn diagnostics(&self) -> Result<Diagnostics, NodeStopped> {
+        self.request(|reply| NodeCommand::Diagnostics { reply })
+            .await
+    }
+
+    /// Rooms recently advertised by nodes nearby.
+    pub async fn discover_rooms(&self) -> Result<Vec<RoomInfo>, NodeStopped> {
+        self.request(|reply| NodeCommand::DiscoverRooms { reply })
+            .await
+    }
+
+    /// Peers which were discovered but we are not connected to via gossip.
+    pub async fn discovered_peers(&self) -> Result<Vec<PublicKey>, NodeStopped> {
+        self.request(|reply| NodeCommand::DiscoveredPeers { reply })
+            .await
+    }
+
+    /// Current neighbours and the most recent triggers, for frontends which just connected.
+    pub async fn snapshot(&self) -> Result<ChannelEvent, NodeStopped> {
+        self.request(|reply| NodeCommand::Snapshot { reply }).await
+    }
+
+    /// Only forward incoming triggers for the given sample indices, `None` allows all.
+    pub async fn set_index_filter(&self, filter: Option<HashSet<u16>>) -> Result<(), NodeStopped> {
+        self.send_command(NodeCommand::SetIndexFilter(filter)).await
+    }
+
+    /// Only forward incoming triggers of the given peer, `None` forwards everyone's again.
+    ///
+    /// Applies before the index filter, triggers of the soloed peer still need to pass it.
+    pub async fn set_solo(&self, peer: Option<PublicKey>) -> Result<(), NodeStopped> {
+        self.send_command(NodeCommand::SetSolo(peer)).await
+    }
+
+    /// Stops forwarding incoming triggers to the frontend while staying in the room, or resumes it.
+    ///
+    /// History and presence are still kept up to date while paused. With `flush_on_resume`, the
+    /// triggers which arrived in the meantime are forwarded on resume, up to the most recent 256,
+    /// otherwise they are skipped.
+    pub async fn set_receive_paused(
+        &self,
+        paused: bool,
+        flush_on_resume: bool,
+    ) -> Result<(), NodeStopped> {
+        self.send_command(NodeCommand::SetReceivePaused {
+            paused,
+            flush_on_resume,
+        })
+        .await
+    }
+
+    /// Picks the sound bank our triggers refer to, and the banks we can play besides it.
+    ///
+    /// Peers publishing triggers of any other bank are reported once as `UnknownBank`, their
+    /// triggers are still forwarded.
+    pub async fn set_bank(
+        &self,
+        bank_id: u16,
+        mut known_banks: HashSet<u16>,
+    ) -> Result<(), NodeStopped> {
+        self.context.lock().await.bank_id = bank_id;
+        known_banks.insert(bank_id);
+        self.send_command(NodeCommand::SetKnownBanks(known_banks))
+            .await
+    }
+
+    /// Switches between publishing triggers and only receiving them.
+    ///
+    /// Listeners keep receiving and syncing, but every publish is rejected with
+    /// `PublishError::ReadOnly`. Our heartbeats announce the role to peers.
+    pub async fn set_role(&self, role: Role) {
+        self.context.lock().await.role = role;
+    }
+
+    pub async fn role(&self) -> Role {
+        self.context.lock().await.role
+    }
+
+    /// Configures whether our own messages are echoed right away or once they were handed to the
+    /// gossip overlay. Messages queued before the change keep the mode they were published with.
+    pub async fn set_echo_mode(&self, echo_mode: EchoMode) {
+        self.context.lock().await.echo_mode = echo_mode;
+    }
+
+    /// Configures how far timestamps of incoming triggers may deviate from our local clock.
+    pub async fn set_skew_check(&self, skew_check: SkewCheck) -> Result<(), NodeStopped> {
+        self.context.lock().await.max_skew = skew_check.max_skew;
+        self.send_command(NodeCommand::SetSkewCheck(skew_check))
+            .await
+    }
+
+    /// Resends all triggers in the history to a connected peer, ours and those of others, for
+    /// example when it joined before they were synced to it, and returns how many are resent.
+    ///
+    /// The triggers go out as replays with the next sync sessions. Sync sessions don't tell who
+    /// is on the other side, so other peers syncing with us receive them as well and drop the
+    /// ones they have already.
+    pub async fn push_history_to(&self, peer: PublicKey) -> anyhow::Result<usize> {
+        self.request(|reply| NodeCommand::PushHistory { peer, reply })
+            .await?
+    }
+
+    /// Subscribes to the room's topic again on the running network, for when gossip stopped
+    /// delivering messages although the connection seems fine.
+    ///
+    /// Lighter than a reconnect, neither the network nor our identity change. Published messages
+    /// are queued until the new subscription is ready.
+    pub async fn resubscribe(&self) -> anyhow::Result<()> {
+        self.request(|reply| NodeCommand::Resubscribe { reply })
+            .await?
+    }
+
+    /// Leaves the current room and joins the given one, rebuilding the network.
+    ///
+    /// The history and messages waiting for sync delivery are cleared, they belong to the
+    /// previous room.
+    pub async fn join_room(&self, room: Room) -> anyhow::Result<()> {
+        self.request(|reply| NodeCommand::JoinRoom { room, reply })
+            .await?
+    }
+
+    /// Collapses triggers of the same sample by the same peer closer together than the window,
+    /// `None` disables coalescing.
+    pub async fn set_coalesce_window(&self, window: Option<Duration>) -> Result<(), NodeStopped> {
+        self.send_command(NodeCommand::SetCoalesceWindow(window))
+            .await
+    }
+
+    /// Caps the number of gossip neighbours, `None` removes the cap.
+    ///
+    /// Once the cap is reached, the least recently active neighbour is evicted for a new one, or
+    /// the new one is rejected when all neighbours are active.
+    pub async fn set_max_peers(&self, max_peers: Option<usize>) -> Result<(), NodeStopped> {
+        self.send_command(NodeCommand::SetMaxPeers(max_peers)).await
+    }
+
+    /// Replaces the node's private key with a freshly generated one and returns the new public
+    /// key.
+    ///
+    /// The network is rebuilt and re-subscribed to the topic, our previous identity leaves the
+    /// gossip overlay before the new one joins. Messages which are in-flight during the swap may
+    /// be lost.
+    pub async fn rotate_identity(&self) -> anyhow::Result<PublicKey> {
+        self.request(|reply| NodeCommand::RotateIdentity { reply })
+            .await?
+    }
+
+    /// Hands a command to the node task without waiting for it to be handled.
+    async fn send_command(&self, command: NodeCommand) -> Result<(), NodeStopped> {
+        self.command_tx.send(command).await.map_err(|_| NodeStopped)
+    }
+
+    /// Hands a command to the node task and waits for its reply.
+    ///
+    /// Fails when the node task stopped before the command was sent, or dropped it unanswered.
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> NodeCommand,
+    ) -> Result<T, NodeStopped> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send_command(command(reply)).await?;
+        reply_rx.await.map_err(|_| NodeStopped)
+    }
+}
+
+/// Task processing all network events and commands of a node.
+struct NodeTask {
+    config: Config,
+    // The private key is re-used for every rebuild of the network so our identity stays stable
+    // across reconnects, it only changes when explicitly rotated.
+    private_key: PrivateKey,
+    room: Room,
+    context: Arc<Mutex<Context>>,
+    connection: Connection,
+    /// Why the most recent attempt to connect failed, cleared once connected.
+    connect_error: Option<String>,
+    events_tx: EventSender,
+    app_rx: mpsc::Receiver<Payload>,
+    command_rx: mpsc::Receiver<NodeCommand>,
+    sync_failures_tx: mpsc::Sender<SyncFailure>,
+    sync_failures_rx: mpsc::Receiver<SyncFailure>,
+    sync_outbox: SyncOutbox,
+    resync: ResyncSwitch,
+    history: Arc<Mutex<History>>,
+    /// Where the history is saved on shutdown, `None` if it is only kept in memory.
+    history_store: Option<HistoryStore>,
+    message_rates: MessageRates,
+    index_filter: Option<HashSet<u16>>,
+    solo: Option<PublicKey>,
+    /// Sound banks we can play, triggers of other banks are reported.
+    known_banks: HashSet<u16>,
+    /// Peers and unknown banks already reported, so each is only reported once.
+    unknown_banks: HashSet<(PublicKey, u16)>,
+    /// Incoming triggers held back while receiving is paused, `None` while it isn't.
+    paused: Option<PausedMessages>,
+    skew_check: SkewCheck,
+    coalesce: Coalesce,
+    heartbeat: Option<Interval>,
+    room_advertisement: Interval,
+    lobby: Lobby,
+    peers: Peers,
+    sync_retries: SyncRetries,
+    resyncs: Resyncs,
+    sync_health: SyncHealth,
+    dedup: Dedup,
+    /// Timestamp of the heartbeat published by a running self test, resolved on its echo.
+    self_test_echo: Option<(u64, oneshot::Sender<()>)>,
+    /// Set once the node was shut down, the task ends instead of reconnecting.
+    stopped: bool,
+}
+
+impl NodeTask {
+    async fn run(mut self) {
+        loop {
+            self.process().await;
+            if self.stopped {
+                return;
+            }
+
+            // One of the network streams terminated, most likely because the underlying
+            // connection dropped. Rebuild it with exponential backoff until we manage to
+            // subscribe again.
+            self.reconnect().await;
+            if self.stopped {
+                return;
+            }
+        }
+    }
+
+    /// Handles events until one of the network streams terminates.
+    async fn process(&mut self) {
+        loop {
+            // Timers which aren't scheduled disable their arm.
+            let report_at = self.peers.report_at();
+            let retry_at = self.sync_retries.next_retry_at();
+            let no_peers_at = self.peers.no_peers_at();
+
+            tokio::select! {
+                result = self.connection.system_events_rx.recv() => {
+                    match result {
+                        Ok(event) => self.on_system_event(event).await,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            // Missed neighbour events leave the peer state out of date until
+                            // the affected peers send the next event.
+                            println!("system events lagged, {skipped} events skipped");
+                            let message = format!(
+                                "missed {skipped} system events, peer state might be outdated"
+                            );
+                            self.emit(ChannelEvent::Error { message }).await;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            println!("system event stream closed");
+                            return;
+                        }
+                    }
+                },
+                event = self.connection.topic_rx.recv() => {
+                    match event {
+                        Some(event) => self.on_network_event(event).await,
+                        None => return,
+                    }
+                },
+                event = self.connection.lobby_rx.recv() => {
+                    match event {
+                        Some(event) => self.on_lobby_event(event).await,
+                        None => return,
+                    }
+                },
+                Some(payload) = self.app_rx.recv() => {
+                    self.on_local_payload(payload).await;
+                },
+                _ = sleep_until(report_at.unwrap_or_else(Instant::now)), if report_at.is_some() => {
+                    if let Some(count) = self.peers.take_count_change() {
+                        self.emit(ChannelEvent::PeerCountChanged { count }).await;
+                    }
+                },
+                _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => {
+                    self.retry_syncs().await;
+                },
+                _ = sleep_until(no_peers_at.unwrap_or_else(Instant::now)),
+                    if no_peers_at.is_some() =>
+                {
+                    if self.peers.take_no_peers() {
+                        println!("no peers found");
+                        self.emit(ChannelEvent::NoPeersFound).await;
+                    }
+                },
+                Some(failure) = self.sync_failures_rx.recv() => {
+                    println!("sync failed: {failure}");
+                    self.emit(ChannelEvent::Error {
+                        message: format!("sync failed: {failure}"),
+                    }).await;
+                },
+                Some(command) = self.command_rx.recv() => {
+                    if self.on_command(command).await.is_break() {
+                        return;
+                    }
+                },
+                _ = tick(&mut self.heartbeat) => {
+                    let listener = self.context.lock().await.role == Role::Listener;
+                    self.publish(Payload::Heartbeat { timestamp: now_millis(), listener }).await;
+                },
+                _ = self.room_advertisement.tick() => {
+                    self.advertise_room().await;
+                },
+            }
+        }
+    }
+
+    /// Publishes a message generated by the node itself on the topic, best-effort.
+    async fn publish(&mut self, payload: Payload) {
+        let bytes = encode_for_room(
+            &self.private_key,
+            self.room.encryption_key.as_ref(),
+            payload,
+        );
+        let _ = self
+            .connection
+            .topic_tx
+            .send(ToNetwork::Message { bytes })
+            .await;
+    }
+
+    /// Advertises our room in the lobby, unless it is private or the default room.
+    async fn advertise_room(&mut self) {
+        let Some(ref name) = self.room.name else {
+            return;
+        };
+
+        // Advertisements are repeated anyway, there's no point in queueing them until we
+        // joined the lobby.
+        if self
+            .context
+            .lock()
+            .await
+            .topics_ready
+            .get(&LOBBY_TOPIC.id())
+            != Some(&true)
+        {
+            return;
+        }
+
+        let bytes = encode_payload(
+            &self.private_key,
+            Payload::RoomAdvertisement {
+                topic: self.room.topic,
+                name: name.clone(),
+                timestamp: now_millis(),
+            },
+        );
+        let _ = self
+            .connection
+            .lobby_tx
+            .send(ToNetwork::Message { bytes })
+            .await;
+    }
+
+    async fn on_lobby_event(&mut self, event: FromNetwork) {
+        let FromNetwork::GossipMessage { bytes, .. } = event else {
+            return;
+        };
+
+        if !self.dedup.insert(&bytes) {
+            return;
+        }
+
+        let Ok(signed) = decode_payload(&bytes, self.config.max_payload_size) else {
+            println!("failed to decode lobby message");
+            return;
+        };
+        if !signed.verify() {
+            return;
+        }
+
+        if let Payload::RoomAdvertisement { topic, name, .. } = signed.payload {
+            self.lobby.seen(topic, name);
+        }
+    }
+
+    async fn emit(&mut self, event: ChannelEvent) {
+        self.events_tx.send(event).await;
+    }
+
+    async fn on_system_event(&mut self, event: p2panda_net::SystemEvent<AppTopic>) {
+        match self.peers.handle_event(&event) {
+            Some(PeerChange::Discovered(peer)) => {
+                println!("discovered peer {peer}");
+                self.emit(ChannelEvent::PeerDiscovered { peer }).await;
+            }
+            Some(PeerChange::Evicted(peer)) => {
+                println!("evicted peer {peer}, peer cap reached");
+                self.emit(ChannelEvent::PeerEvicted { peer }).await;
+            }
+            Some(PeerChange::Rejected(peer)) => {
+                println!("rejected peer {peer}, peer cap reached");
+                self.emit(ChannelEvent::PeerRejected { peer }).await;
+            }
+            None => (),
+        }
+
+        self.sync_health.handle_event(&event);
+
+        // Seeds hand their history to everyone joining the room, with nobody else around the
+        // joiner wouldn't catch up at all.
+        if let p2panda_net::SystemEvent::GossipNeighborUp { topic_id, peer } = event {
+            if self.config.seed && topic_id == self.room.topic.id() {
+                let triggers = self.history.lock().await.to_stored();
+                println!("replaying {} triggers for {peer}", triggers.len());
+                self.replay(triggers).await;
+            }
+        }
+
+        match self.sync_retries.handle_event(&event) {
+            Some(RetryProgress::Scheduled {
+                peer,
+                attempt,
+                delay,
+                ..
+            }) => {
+                println!("sync with {peer} failed, retry {attempt} in {delay:?}");
+            }
+            Some(RetryProgress::GaveUp {
+                peer,
+                topic_id,
+                attempts,
+            }) => {
+                println!("giving up syncing with {peer} after {attempts} retries");
+                self.emit(ChannelEvent::SyncGaveUp {
+                    peer,
+                    topic_id,
+                    attempts,
+                })
+                .await;
+            }
+            None => (),
+        }
+
+        if let Some((peer, phase)) = self.resyncs.handle_event(&event) {
+            println!("resync with {peer}: {phase:?}");
+            self.emit(ChannelEvent::Resync { peer, phase }).await;
+        }
+
+        let sync_progress = match event {
+            p2panda_net::SystemEvent::SyncStarted { peer, .. } => Some((peer, SyncState::Started)),
+            p2panda_net::SystemEvent::SyncDone { peer, .. } => Some((peer, SyncState::Done)),
+            p2panda_net::SystemEvent::SyncFailed { peer, .. } => Some((peer, SyncState::Failed)),
+            _ => None,
+        };
+        if let Some((peer, state)) = sync_progress {
+            self.emit(ChannelEvent::SyncProgress { peer, state }).await;
+        }
+
+        self.emit(ChannelEvent::SystemEvent(SystemEvent(event)))
+            .await;
+    }
+
+    async fn on_local_payload(&mut self, payload: Payload) {
+        match payload {
+            Payload::Trigger {
+                timestamp,
+                sample_index,
+                seq,
+                bank_id,
+            } => {
+                let public_key = self.private_key.public_key();
+                if !self.coalesce.accept(public_key, sample_index, timestamp) {
+                    return;
+                }
+
+                let message = ApplicationMessage {
+                    timestamp,
+                    sample_index,
+                    public_key,
+                    seq,
+                    bank_id,
+                    replay: false,
+                    topic_id: self.room.topic.id(),
+                    hue: peer_hue(&public_key),
+                };
+                // Signatures are deterministic, encoding the trigger again reproduces the bytes
+                // it was published in.
+                let bytes = encode_payload(&self.private_key, payload);
+                self.history.lock().await.push(StoredTrigger {
+                    message: message.clone(),
+                    bytes,
+                });
+                self.emit(ChannelEvent::ApplicationMessage(message)).await;
+            }
+            Payload::Control(kind) => {
+                self.emit(ChannelEvent::Control { kind }).await;
+            }
+            Payload::Raw(bytes) => {
+                let public_key = self.private_key.public_key();
+                self.emit(ChannelEvent::RawMessage { public_key, bytes })
+                    .await;
+            }
+            Payload::Heartbeat { timestamp, .. } => {
+                // Only the self test passes heartbeats through the echo path.
+                if let Some((expected, echo_tx)) = self.self_test_echo.take() {
+                    if expected == timestamp {
+                        let _ = echo_tx.send(());
+                    } else {
+                        self.self_test_echo = Some((expected, echo_tx));
+                    }
+                }
+            }
+            Payload::RangeRequest { .. }
+            | Payload::RangeUnavailable { .. }
+            | Payload::RoomAdvertisement { .. }
+            | Payload::Replay { .. } => (),
+        }
+    }
+
+    async fn on_network_event(&mut self, event: FromNetwork) {
+        let (bytes, delivered_from) = match event {
+            FromNetwork::GossipMessage {
+                bytes,
+                delivered_from,
+            } => (bytes, delivered_from),
+            // Only the `Data` of sync sessions arrives here, handshakes surface as system events.
+            // Our sync protocol delivers the published message as a whole in the header,
+            // protocols splitting off a header of their own carry it in the payload.
+            FromNetwork::SyncMessage {
+                header,
+                payload,
+                delivered_from,
+            } => (payload.unwrap_or(header), delivered_from),
+        };
+        self.peers.record_message(delivered_from);
+        self.message_rates.record_received();
+
+        // Whoever can't encrypt for the room has no business in it, so this isn't worth an
+        // error event.
+        let bytes = match encryption::open(self.room.encryption_key.as_ref(), bytes) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                println!("dropped message from {delivered_from}: {err}");
+                return;
+            }
+        };
+
+        // The same message can reach us via gossip and sync, and again with every resync.
+        // Encrypted copies differ in their nonce, so duplicates are detected after decrypting.
+        if !self.dedup.insert(&bytes) {
+            println!("dropped duplicate message");
+            return;
+        }
+
+        // Peers running another version might send payloads we don't understand, or exceed the
+        // size limit, that must never take the node down.
+        let signed = match decode_payload(&bytes, self.config.max_payload_size) {
+            Ok(signed) => signed,
+            Err(err) => {
+                let message = format!(
+                    "failed to decode message of {} bytes from {delivered_from}: {err}",
+                    bytes.len()
+                );
+                println!("{message}");
+                self.emit(ChannelEvent::Error { message }).await;
+                return;
+            }
+        };
+
+        // Only trust the sender's identity if they could prove it.
+        if !signed.verify() {
+            println!(
+                "dropped message with invalid signature from {}",
+                signed.public_key
+            );
+            return;
+        }
+
+        let public_key = signed.public_key;
+        // Limited neighbours still relay messages of others, only the ones they published
+        // themselves are dropped.
+        if self.peers.is_limited(&public_key) {
+            return;
+        }
+        self.peers.seen(public_key);
+
+        let (timestamp, sample_index, seq, bank_id) = match signed.payload {
+            Payload::Trigger {
+                timestamp,
+                sample_index,
+                seq,
+                bank_id,
+            } => (timestamp, sample_index, seq, bank_id),
+            Payload::Replay { message } => {
+                self.on_replay(public_key, message).await;
+                return;
+            }
+            Payload::Control(kind) => {
+                println!("received control message {kind:?} from {public_key}");
+                self.emit(ChannelEvent::Control { kind }).await;
+                return;
+            }
+            Payload::Raw(bytes) => {
+                self.emit(ChannelEvent::RawMessage { public_key, bytes })
+                    .await;
+                return;
+            }
+            Payload::Heartbeat { listener, .. } => {
+                self.peers.set_listener(public_key, listener);
+                return;
+            }
+            Payload::RoomAdvertisement { .. } => return,
+            Payload::RangeRequest { peer, from, to } => {
+                // Seeds keep the history available for peers which are offline.
+                if peer == self.private_key.public_key() || self.config.seed {
+                    self.serve_range(public_key, peer, from, to).await;
+                }
+                return;
+            }
+            Payload::RangeUnavailable {
+                requester,
+                from,
+                to,
+            } => {
+                if requester == self.private_key.public_key() {
+                    self.emit(ChannelEvent::Error {
+                        message: format!(
+                            "{public_key} can't resend triggers {from} to {to}, range unavailable"
+                        ),
+                    })
+                    .await;
+                }
+                return;
+            }
+        };
+
+        let message = ApplicationMessage {
+            timestamp,
+            sample_index,
+            public_key,
+            seq,
+            bank_id,
+            replay: false,
+            topic_id: self.room.topic.id(),
+            hue: peer_hue(&public_key),
+        };
+        self.on_trigger(message, bytes).await;
+    }
+
+    /// Handles a trigger resent from the history of `relayed_by`, which isn't necessarily its
+    /// author.
+    async fn on_replay(&mut self, relayed_by: PublicKey, bytes: Vec<u8>) {
+        // We might have received the trigger when it was published.
+        if !self.dedup.insert(&bytes) {
+            return;
+        }
+
+        let signed = match decode_payload(&bytes, self.config.max_payload_size) {
+            Ok(signed) => signed,
+            Err(err) => {
+                println!("failed to decode trigger replayed by {relayed_by}: {err}");
+                return;
+            }
+        };
+        if !signed.verify() {
+            println!("dropped replayed trigger with invalid signature from {relayed_by}");
+            return;
+        }
+
+        let public_key = signed.public_key;
+        if self.peers.is_limited(&public_key) {
+            return;
+        }
+        let Payload::Trigger {
+            timestamp,
+            sample_index,
+            seq,
+            bank_id,
+        } = signed.payload
+        else {
+            println!("dropped replay of another message than a trigger from {relayed_by}");
+            return;
+        };
+
+        // Triggers restored from a saved history aren't known to the dedup.
+        if self.history.lock().await.contains(public_key, seq) {
+            return;
+        }
+
+        let message = ApplicationMessage {
+            timestamp,
+            sample_index,
+            public_key,
+            seq,
+            bank_id,
+            replay: true,
+            topic_id: self.room.topic.id(),
+            hue: peer_hue(&public_key),
+        };
+        self.on_trigger(message, bytes).await;
+    }
+
+    /// Keeps a received trigger in the history and passes it on to the frontend, `bytes` being
+    /// the message it was published in.
+    async fn on_trigger(&mut self, mut message: ApplicationMessage, bytes: Vec<u8>) {
+        let ApplicationMessage {
+            timestamp,
+            sample_index: index,
+            public_key,
+            bank_id,
+            replay,
+            ..
+        } = message;
+
+        message.timestamp = match self.skew_check.check_trigger(timestamp, replay) {
+            SkewDecision::Accepted(timestamp) => timestamp,
+            SkewDecision::Clamped(now) => {
+                println!("timestamp {timestamp} exceeds clock skew, clamped to {now}");
+                now
+            }
+            SkewDecision::Dropped => {
+                println!("timestamp {timestamp} exceeds clock skew, dropped message");
+                return;
+            }
+        };
+
+        // Replays aren't played, there is nothing to coalesce.
+        if !replay && !self.coalesce.accept(public_key, index, message.timestamp) {
+            println!(
+                "coalesced trigger {index} from {public_key} at {}",
+                message.timestamp
+            );
+            return;
+        }
+
+        if !self.known_banks.contains(&bank_id) && self.unknown_banks.insert((public_key, bank_id))
+        {
+            println!("{public_key} plays unknown bank {bank_id}");
+            self.emit(ChannelEvent::UnknownBank {
+                peer: public_key,
+                bank_id,
+            })
+            .await;
+        }
+
+        self.history.lock().await.push(StoredTrigger {
+            message: message.clone(),
+            bytes,
+        });
+
+        if self.solo.is_some_and(|solo| solo != public_key) {
+            return;
+        }
+
+        if let Some(ref indices) = self.index_filter {
+            if !indices.contains(&index) {
+                return;
+            }
+        }
+
+        if let Some(ref mut paused) = self.paused {
+            paused.push(message);
+            return;
+        }
+
+        self.emit(ChannelEvent::ApplicationMessage(message)).await;
+    }
+
+    /// Resends the triggers of `author` in the requested range via sync, or tells the requester
+    /// that the range is not in our history anymore.
+    ///
+    /// Only the author itself reports unavailable ranges, a seed missing them might just have
+    /// joined later than the author published them.
+    async fn serve_range(&mut self, requester: PublicKey, author: PublicKey, from: u64, to: u64) {
+        let triggers = self.history.lock().await.range(author, from, to);
+
+        let Some(triggers) = triggers else {
+            println!("range {from} to {to} of {author} requested by {requester} is unavailable");
+            if author == self.private_key.public_key() {
+                self.publish(Payload::RangeUnavailable {
+                    requester,
+                    from,
+                    to,
+                })
+                .await;
+            }
+            return;
+        };
+
+        println!(
+            "resending {} triggers of {author} from {from} to {to} requested by {requester}",
+            triggers.len()
+        );
+        self.replay(triggers).await;
+    }
+
+    /// Queues triggers from the history to be resent with the next sync sessions.
+    ///
+    /// They are resent as published by their authors, wrapped in replays so peers keep their
+    /// original timestamps and can tell them from live triggers.
+    async fn replay(&mut self, triggers: Vec<StoredTrigger>) {
+        for trigger in triggers {
+            let bytes = encode_for_room(
+                &self.private_key,
+                self.room.encryption_key.as_ref(),
+                Payload::Replay {
+                    message: trigger.bytes,
+                },
+            );
+            self.sync_outbox.push(bytes).await;
+        }
+    }
+
+    /// Queues all triggers in the history to be resent via sync, if the peer is a neighbour.
+    async fn push_history_to(&mut self, peer: PublicKey) -> anyhow::Result<usize> {
+        if !self.peers.neighbours().contains(&peer) {
+            anyhow::bail!("peer {peer} is not connected");
+        }
+
+        let triggers = self.history.lock().await.to_stored();
+        let count = triggers.len();
+        println!("pushing {count} triggers to {peer}");
+        self.replay(triggers).await;
+        Ok(count)
+    }
+
+    async fn on_command(&mut self, command: NodeCommand) -> ControlFlow<()> {
+        match command {
+            NodeCommand::RotateIdentity { reply } => {
+                match self.rebuild(PrivateKey::new(), self.room.clone()).await {
+                    Ok(()) => {
+                        // Sequence numbers count per identity.
+                        self.context.lock().await.next_seq = 0;
+                        println!("identity rotated: {}", self.private_key.public_key());
+                        let _ = reply.send(Ok(self.private_key.public_key()));
+                    }
+                    Err(err) => {
+                        println!("failed to rotate identity: {err:#}");
+                        let _ = reply.send(Err(err));
+                        // The old network is gone already, fall through to reconnecting with
+                        // our previous identity.
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            NodeCommand::JoinRoom { room, reply } => {
+                let topic = room.topic;
+                match self.rebuild(self.private_key.clone(), room).await {
+                    Ok(()) => {
+                        println!("joined room {:?}", topic);
+                        self.history.lock().await.clear();
+                        if let Some(ref mut paused) = self.paused {
+                            paused.messages.clear();
+                        }
+                        self.sync_outbox.clear().await;
+                        self.sync_retries.clear();
+                        self.emit(ChannelEvent::HistoryCleared).await;
+                        let _ = reply.send(Ok(()));
+                    }
+                    Err(err) => {
+                        println!("failed to join room: {err:#}");
+                        let _ = reply.send(Err(err));
+                        // Fall through to reconnecting to the room we were in before.
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            NodeCommand::ClearHistory { reply } => {
+                self.history.lock().await.clear();
+                // Otherwise the cleared triggers would come back on the next start.
+                if let Some(ref history_store) = self.history_store {
+                    if let Err(err) = history_store.clear() {
+                        println!("failed to remove saved history: {err:#}");
+                    }
+                }
+                self.emit(ChannelEvent::HistoryCleared).await;
+                let _ = reply.send(());
+            }
+            NodeCommand::PeerQuality { reply } => {
+                let _ = reply.send(self.peers.quality());
+            }
+            NodeCommand::Diagnostics { reply } => {
+                let _ = reply.send(self.diagnostics());
+            }
+            NodeCommand::SelfTest { timestamp, reply } => {
+                let stages = self.connection_stages().await;
+                let (echo_tx, echo_rx) = oneshot::channel();
+                self.self_test_echo = Some((timestamp, echo_tx));
+                let _ = reply.send((stages, echo_rx));
+            }
+            NodeCommand::Shutdown { reply } => {
+                println!("shutting down");
+                self.stopped = true;
+                // Saved first, so a network which doesn't shut down in time can't prevent it.
+                if let Some(ref history_store) = self.history_store {
+                    let triggers = self.history.lock().await.to_stored();
+                    if let Err(err) = history_store.save(triggers) {
+                        println!("failed to save history: {err:#}");
+                    }
+                }
+                if timeout(NETWORK_SHUTDOWN_TIMEOUT, self.connection.shutdown())
+                    .await
+                    .is_err()
+                {
+                    println!("network didn't shut down within {NETWORK_SHUTDOWN_TIMEOUT:?}");
+                }
+                let _ = reply.send(());
+                return ControlFlow::Break(());
+            }
+            NodeCommand::SyncHealth { reply } => {
+                let _ = reply.send(self.sync_health.peers());
+            }
+            NodeCommand::FullState { queue_depth, reply } => {
+                let topics_ready = self.context.lock().await.topics_ready.clone();
+                let _ = reply.send(AppStateSnapshot {
+                    public_key: self.private_key.public_key(),
+                    topic: self.room.topic,
+                    topics_ready,
+                    room_name: self.room.name.clone(),
+                    peers: self.peers.states(),
+                    recent_triggers: self.history.lock().await.to_messages(),
+                    message_rate: self.message_rates.rate(),
+                    queue_depth,
+                });
+            }
+            NodeCommand::PushHistory { peer, reply } => {
+                let _ = reply.send(self.push_history_to(peer).await);
+            }
+            NodeCommand::Resubscribe { reply } => {
+                let result = self.resubscribe().await;
+                match result {
+                    Ok(()) => println!("resubscribed to {:?}", self.room.topic),
+                    Err(ref err) => println!("failed to resubscribe: {err:#}"),
+                }
+                let _ = reply.send(result);
+            }
+            NodeCommand::DiscoverRooms { reply } => {
+                let _ = reply.send(self.lobby.rooms());
+            }
+            NodeCommand::Presence { reply } => {
+                let _ = reply.send(self.peers.presence());
+            }
+            NodeCommand::DiscoveredPeers { reply } => {
+                let _ = reply.send(self.peers.discovered_only());
+            }
+            NodeCommand::Snapshot { reply } => {
+                let _ = reply.send(ChannelEvent::Snapshot {
+                    peers: self.peers.neighbours(),
+                    recent_triggers: self.history.lock().await.recent(SNAPSHOT_MAX_TRIGGERS),
+                });
+            }
+            NodeCommand::SetIndexFilter(filter) => {
+                self.index_filter = filter;
+            }
+            NodeCommand::SetSolo(peer) => {
+                self.solo = peer;
+            }
+            NodeCommand::SetKnownBanks(known_banks) => {
+                self.known_banks = known_banks;
+                // Report peers again which play a bank we don't know anymore.
+                self.unknown_banks.clear();
+            }
+            NodeCommand::SetReceivePaused {
+                paused: true,
+                flush_on_resume,
+            } => {
+                // Pausing again only changes what happens on resume.
+                self.paused
+                    .get_or_insert_with(PausedMessages::default)
+                    .flush_on_resume = flush_on_resume;
+            }
+            NodeCommand::SetReceivePaused { paused: false, .. } => {
+                if let Some(paused) = self.paused.take() {
+                    if paused.dropped > 0 {
+                        println!("dropped {} triggers while paused", paused.dropped);
+                        self.emit(ChannelEvent::Dropped {
+                            count: paused.dropped,
+                            reason: DropReason::ReceivePaused,
+                        })
+                        .await;
+                    }
+                    if paused.flush_on_resume {
+                        for message in paused.messages {
+                            self.emit(ChannelEvent::ApplicationMessage(message)).await;
+                        }
+                    }
+                }
+            }
+            NodeCommand::SetSkewCheck(skew_check) => {
+                self.skew_check = skew_check;
+            }
+            NodeCommand::SetCoalesceWindow(window) => {
+                self.coalesce.set_window(window);
+            }
+            NodeCommand::SetMaxPeers(max_peers) => {
+                for peer in self.peers.set_max_peers(max_peers) {
+                    println!("evicted peer {peer}, peer cap lowered");
+                    self.emit(ChannelEvent::PeerEvicted { peer }).await;
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Self test stages telling how far connecting to the network got.
+    async fn connection_stages(&self) -> Vec<StageReport> {
+        if self.config.local_only {
+            return [
+                SelfTestStage::NetworkBuilt,
+                SelfTestStage::Subscribed,
+                SelfTestStage::TopicReady,
+            ]
+            .into_iter()
+            .map(|stage| StageReport::skipped(stage, "running local-only"))
+            .collect();
+        }
+
+        if self.connection.network.is_none() {
+            let detail = self
+                .connect_error
+                .clone()
+                .unwrap_or_else(|| "not connected to a network".to_string());
+            return vec![
+                StageReport::failed(SelfTestStage::NetworkBuilt, detail),
+                StageReport::skipped(SelfTestStage::Subscribed, "network wasn't built"),
+                StageReport::skipped(SelfTestStage::TopicReady, "network wasn't built"),
+            ];
+        }
+
+        let subscribed = if self.connection.topic_tx.is_closed() {
+            StageReport::failed(SelfTestStage::Subscribed, "topic subscription closed")
+        } else {
+            StageReport::passed(SelfTestStage::Subscribed)
+        };
+        let topic_ready = if self.context.lock().await.topic_ready() {
+            StageReport::passed(SelfTestStage::TopicReady)
+        } else {
+            StageReport::failed(
+                SelfTestStage::TopicReady,
+                "didn't join the gossip overlay yet, no peer was reached",
+            )
+        };
+
+        vec![
+            StageReport::passed(SelfTestStage::NetworkBuilt),
+            subscribed,
+            topic_ready,
+        ]
+    }
+
+    fn diagnostics(&self) -> Diagnostics {
+        let (listening_addresses, relay, discovery) = match self.connection.network {
+            Some(ref network) => {
+                let endpoint = network.endpoint();
+                let (ipv4, ipv6) = endpoint.bound_sockets();
+                let addresses = std::iter::once(ipv4)
+                    .chain(ipv6)
+                    .map(|addr| addr.to_string())
+                    .collect();
+                let relay = endpoint.home_relay().map(|url| url.to_string());
+                (addresses, relay, discovery_mechanisms(&self.config))
+            }
+            None => (Vec::new(), None, Vec::new()),
+        };
+
+        let network_id = self
+            .room
+            .network_id
+            .unwrap_or_else(|| self.config.network_id());
+        let last_peer_discovered_at = self.peers.last_discovered_at();
+        let cutoff = now_millis().saturating_sub(DISCOVERY_WINDOW.as_millis() as u64);
+
+        Diagnostics {
+            public_key: self.private_key.public_key(),
+            network_id: hex::encode(network_id),
+            topic: hex::encode(self.room.topic.id()),
+            listening_addresses,
+            discovery,
+            interfaces: network_interfaces(),
+            sync_protocol: self.config.sync_protocol.name(),
+            relay,
+            local_only: self.config.local_only,
+            peer_count: self.peers.neighbours().len(),
+            last_peer_discovered_at,
+            discovery_active: last_peer_discovered_at.is_some_and(|at| at >= cutoff),
+        }
+    }
+
+    async fn reconnect(&mut self) {
+        println!("network streams closed, reconnecting");
+        self.connection.shutdown().await;
+        // Commands handled while reconnecting must not use the old network anymore.
+        self.connection = Connection::closed();
+
+        let mut attempt = 0;
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        let (connection, topic_ready) = loop {
+            attempt += 1;
+            self.emit(ChannelEvent::Reconnecting { attempt }).await;
+
+            // Keep handling commands during the backoff, shutting down must not wait for it.
+            let backoff_elapsed = sleep(backoff);
+            tokio::pin!(backoff_elapsed);
+            loop {
+                tokio::select! {
+                    _ = &mut backoff_elapsed => break,
+                    Some(command) = self.command_rx.recv() => {
+                        // Failed rebuilds break as well, we are reconnecting already.
+                        let _ = self.on_command(command).await;
+                        if self.stopped {
+                            return;
+                        }
+                        // Joining a room or rotating the identity connected us in the meantime.
+                        if self.connection.network.is_some() {
+                            println!("connected by a command while reconnecting");
+                            return;
+                        }
+                    },
+                }
+            }
+
+            match connect(
+                &self.config,
+                &self.room,
+                self.private_key.clone(),
+                self.sync_failures_tx.clone(),
+                self.sync_outbox.clone(),
+                self.resync.clone(),
+            )
+            .await
+            {
+                Ok(result) => break result,
+                Err(err) => {
+                    println!("reconnect attempt {attempt} failed: {err:#}");
+                    self.connect_error = Some(format!("{err:#}"));
+                    backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                }
+            }
+        };
+        self.install_connection(connection, topic_ready).await;
+
+        println!("reconnected after {attempt} attempt(s)");
+        self.emit(ChannelEvent::Reconnected).await;
+    }
+
+    /// Swaps the topic subscription for a fresh one on the same network.
+    async fn resubscribe(&mut self) -> anyhow::Result<()> {
+        let Some(ref network) = self.connection.network else {
+            anyhow::bail!("not connected to a network");
+        };
+
+        let (topic_tx, topic_rx, topic_ready) = network
+            .subscribe(self.room.topic)
+            .await
+            .context("resubscribing to room topic")?;
+
+        {
+            let mut context = self.context.lock().await;
+            context.topic_tx = topic_tx.clone();
+            context.topics_ready.insert(self.room.topic.id(), false);
+        }
+        flush_when_ready(
+            self.context.clone(),
+            topic_tx.clone(),
+            topic_ready,
+            self.events_tx.clone(),
+        );
+
+        // Dropping the old receiver discards whatever it still buffered, so no message is
+        // handled from both subscriptions.
+        self.connection.topic_tx = topic_tx;
+        self.connection.topic_rx = topic_rx;
+
+        Ok(())
+    }
+
+    /// Syncs the room's topic again for all retries which are due.
+    ///
+    /// Subscribing again makes the network start sync sessions with everyone on the topic, so
+    /// one resubscribe covers all peers due at the same time.
+    async fn retry_syncs(&mut self) {
+        let due = self
+            .sync_retries
+            .take_due()
+            .into_iter()
+            .filter(|retry| retry.topic == self.room.topic)
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            return;
+        }
+
+        for retry in &due {
+            println!(
+                "retrying sync with {}, attempt {}",
+                retry.peer, retry.attempt
+            );
+            self.emit(ChannelEvent::SyncRetry {
+                peer: retry.peer,
+                topic_id: retry.topic.id(),
+                attempt: retry.attempt,
+            })
+            .await;
+        }
+        if let Err(err) = self.resubscribe().await {
+            println!("failed to retry sync: {err:#}");
+        }
+    }
+
+    /// Replaces the running network with one built for the given identity and room.
+    ///
+    /// The old network is shut down first so our previous identity leaves the gossip overlay
+    /// before the new one joins. On failure identity and room stay unchanged.
+    async fn rebuild(&mut self, private_key: PrivateKey, room: Room) -> anyhow::Result<()> {
+        self.connection.shutdown().await;
+
+        let (connection, topic_ready) = connect(
+            &self.config,
+            &room,
+            private_key.clone(),
+            self.sync_failures_tx.clone(),
+            self.sync_outbox.clone(),
+            self.resync.clone(),
+        )
+        .await?;
+
+        self.private_key = private_key;
+        self.room = room;
+        self.install_connection(connection, topic_ready).await;
+
+        Ok(())
+    }
+
+    /// Swaps a freshly built connection in, both for the task and the shared context.
+    async fn install_connection(
+        &mut self,
+        mut connection: Connection,
+        topic_ready: oneshot::Receiver<()>,
+    ) {
+        {
+            let mut context = self.context.lock().await;
+            context.network = connection.network.clone();
+            context.private_key = self.private_key.clone();
+            context.topic = self.room.topic;
+            context.room_key = self.room.encryption_key.clone();
+            context.topic_tx = connection.topic_tx.clone();
+            context.lobby_tx = connection.lobby_tx.clone();
+            context.reset_readiness();
+        }
+
+        flush_when_ready(
+            self.context.clone(),
+            connection.topic_tx.clone(),
+            topic_ready,
+            self.events_tx.clone(),
+        );
+        if let Some(lobby_ready) = connection.lobby_ready.take() {
+            mark_lobby_when_ready(
+                self.context.clone(),
+                connection.lobby_tx.clone(),
+                lobby_ready,
+                self.events_tx.clone(),
+            );
+        }
+        self.connection = connection;
+        self.connect_error = None;
+        self.peers.clear();
+        self.resyncs.clear();
+    }
+}
+
+/// Incoming triggers which arrived while receiving was paused.
+#[derive(Debug, Default)]
+struct PausedMessages {
+    messages: VecDeque<ApplicationMessage>,
+    /// Whether the messages are forwarded on resume or skipped.
+    flush_on_resume: bool,
+    /// Number of messages dropped because the buffer was full.
+    dropped: u64,
+}
+
+impl PausedMessages {
+    fn push(&mut self, message: ApplicationMessage) {
+        if !self.flush_on_resume {
+            return;
+        }
+        if self.messages.len() == MAX_PAUSED_MESSAGES {
+            self.messages.pop_front();
+            self.dropped += 1;
+        }
+        self.messages.push_back(message);
+    }
+}
+
+/// Waits for the next tick of the interval, or forever if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Sends events to the receiver returned by `Node::spawn` and to all `Node::events` streams.
+#[derive(Clone)]
+struct EventSender {
+    tx: mpsc::Sender<ChannelEvent>,
+    broadcast_tx: broadcast::Sender<ChannelEvent>,
+    /// Events dropped because the receiver was full, since the last `Dropped` event.
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSender {
+    async fn send(&self, event: ChannelEvent) {
+        // Nobody might be subscribed to the stream, or listening to the events of a headless
+        // node at all.
+        let _ = self.broadcast_tx.send(event.clone());
+
+        // Waiting for a receiver which isn't drained would stall the node, or deadlock it when
+        // the receiver waits for the node in turn.
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let notice = ChannelEvent::Dropped {
+                count: dropped,
+                reason: DropReason::ReceiverFull,
+            };
+            if self.tx.try_send(notice).is_err() {
+                self.dropped.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(event) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Handles to a built network which is subscribed to the application topic.
+struct Connection {
+    /// `None` when running local-only.
+    network: Option<Network<AppTopic>>,
+    system_events_rx: broadcast::Receiver<p2panda_net::SystemEvent<AppTopic>>,
+    topic_tx: mpsc::Sender<ToNetwork>,
+    topic_rx: mpsc::Receiver<FromNetwork>,
+    lobby_tx: mpsc::Sender<ToNetwork>,
+    lobby_rx: mpsc::Receiver<FromNetwork>,
+    /// Resolves once we joined the lobby's gossip overlay, taken when the connection is installed.
+    lobby_ready: Option<oneshot::Receiver<()>>,
+    shutdown: CancellationToken,
+    /// Keeps the streams of a local-only connection open, they never yield anything.
+    _local: Option<(
+        broadcast::Sender<p2panda_net::SystemEvent<AppTopic>>,
+        mpsc::Sender<FromNetwork>,
+        mpsc::Sender<FromNetwork>,
+    )>,
+}
+
+impl Connection {
+    /// Connection which doesn't touch the network at all.
+    fn local() -> Self {
+        let (system_events_tx, system_events_rx) = broadcast::channel(1);
+        let (topic_tx, _) = mpsc::channel(1);
+        let (from_network_tx, topic_rx) = mpsc::channel(1);
+        let (lobby_tx, _) = mpsc::channel(1);
+        let (from_lobby_tx, lobby_rx) = mpsc::channel(1);
+
+        Self {
+            network: None,
+            system_events_rx,
+            topic_tx,
+            topic_rx,
+            lobby_tx,
+            lobby_rx,
+            lobby_ready: None,
+            shutdown: CancellationToken::new(),
+            _local: Some((system_events_tx, from_network_tx, from_lobby_tx)),
+        }
+    }
+
+    /// Connection whose streams are closed already, so it's replaced on the next reconnect.
+    fn closed() -> Self {
+        Self {
+            _local: None,
+            ..Self::local()
+        }
+    }
+
+    /// Aborts running sync sessions and shuts the network down.
+    async fn shutdown(&self) {
+        self.shutdown.cancel();
+        let Some(ref network) = self.network else {
+            return;
+        };
+        if let Err(err) = network.clone().shutdown().await {
+            println!("failed to shut down network: {err}");
+        }
+    }
+}
+
+async fn connect(
+    config: &Config,
+    room: &Room,
+    private_key: PrivateKey,
+    sync_failures_tx: mpsc::Sender<SyncFailure>,
+    sync_outbox: SyncOutbox,
+    resync: ResyncSwitch,
+) -> anyhow::Result<(Connection, oneshot::Receiver<()>)> {
+    if config.local_only {
+        println!("running local-only, no network is built");
+        // The topic never becomes ready, nothing is ever sent on it.
+        let (_, topic_ready) = oneshot::channel();
+        return Ok((Connection::local(), topic_ready));
+    }
+
+    let shutdown = CancellationToken::new();
+    let network = build_network(
+        config,
+        room,
+        private_key,
+        sync_failures_tx,
+        sync_outbox,
+        resync,
+        shutdown.clone(),
+    )
+    .await?;
+    let system_events_rx = network
+        .events()
+        .await
+        .context("subscribing to system events")?;
+    let (topic_tx, topic_rx, topic_ready) = network
+        .subscribe(room.topic)
+        .await
+        .context("subscribing to room topic")?;
+    let (lobby_tx, lobby_rx, lobby_ready) = network
+        .subscribe(LOBBY_TOPIC)
+        .await
+        .context("subscribing to lobby topic")?;
+
+    let connection = Connection {
+        network: Some(network),
+        system_events_rx,
+        topic_tx,
+        topic_rx,
+        lobby_tx,
+        lobby_rx,
+        lobby_ready: Some(lobby_ready),
+        shutdown,
+        _local: None,
+    };
+
+    Ok((connection, topic_ready))
+}
+
+/// Event confirming that a trigger was handed to the gossip overlay.
+fn published_event(message: &Payload) -> Option<ChannelEvent> {
+    match *message {
+        Payload::Trigger {
+            timestamp,
+            sample_index,
+            ..
+        } => Some(ChannelEvent::Published {
+            timestamp,
+            sample_index,
+        }),
+        _ => None,
+    }
+}
+
+/// Marks the topic as ready once we joined the gossip overlay, publishes all messages which
+/// were queued up until then and announces that we are connected.
+fn flush_when_ready(
+    context: Arc<Mutex<Context>>,
+    topic_tx: mpsc::Sender<ToNetwork>,
+    topic_ready: oneshot::Receiver<()>,
+    events_tx: EventSender,
+) {
+    tokio::spawn(async move {
+        if topic_ready.await.is_err() {
+            return;
+        }
+
+        let mut context = context.lock().await;
+
+        // The network might have been rebuilt in the meantime, in that case this readiness
+        // signal belongs to a stale subscription.
+        if !context.topic_tx.same_channel(&topic_tx) {
+            return;
+        }
+
+        let topic = context.topic;
+        context.topics_ready.insert(topic.id(), true);
+        let mut events = vec![ChannelEvent::TopicReady {
+            topic_id: topic.id(),
+        }];
+        let mut echoes = Vec::new();
+        while let Some(PendingMessage {
+            payload: message,
+            echo,
+        }) = context.pending_messages.pop_front()
+        {
+            let bytes = encode_for_room(
+                &context.private_key,
+                context.room_key.as_ref(),
+                message.clone(),
+            );
+            if let Err(err) = context.topic_tx.send(ToNetwork::Message { bytes }).await {
+                // The network went away while flushing, the rest stays queued for the next one.
+                println!("failed to publish queued message: {err}");
+                if let Payload::Trigger {
+                    timestamp,
+                    sample_index,
+                    ..
+                } = message
+                {
+                    events.push(ChannelEvent::PublishFailed {
+                        timestamp,
+                        sample_index,
+                        reason: "network closed while publishing".to_string(),
+                    });
+                }
+                break;
+            }
+            println!("queued message published: {:?}", message);
+            events.extend(published_event(&message));
+            if echo {
+                echoes.push(message);
+            }
+        }
+
+        // Echoing waits for the node task, which might be waiting for the context itself.
+        let app_tx = context.app_tx.clone();
+        drop(context);
+        for message in echoes {
+            let _ = app_tx.send(message).await;
+        }
+        for event in events {
+            events_tx.send(event).await;
+        }
+        events_tx.send(ChannelEvent::Connected { topic }).await;
+    });
+}
+
+/// Marks the lobby topic as ready once we joined its gossip overlay.
+fn mark_lobby_when_ready(
+    context: Arc<Mutex<Context>>,
+    lobby_tx: mpsc::Sender<ToNetwork>,
+    lobby_ready: oneshot::Receiver<()>,
+    events_tx: EventSender,
+) {
+    tokio::spawn(async move {
+        if lobby_ready.await.is_err() {
+            return;
+        }
+
+        let mut context = context.lock().await;
+        if !context.lobby_tx.same_channel(&lobby_tx) {
+            return;
+        }
+        context.topics_ready.insert(LOBBY_TOPIC.id(), true);
+        drop(context);
+
+        events_tx
+            .send(ChannelEvent::TopicReady {
+                topic_id: LOBBY_TOPIC.id(),
+            })
+            .await;
+    });
+}
+
+async fn build_network(
+    config: &Config,
+    room: &Room,
+    private_key: PrivateKey,
+    sync_failures_tx: mpsc::Sender<SyncFailure>,
+    sync_outbox: SyncOutbox,
+    resync: ResyncSwitch,
+    shutdown: CancellationToken,
+) -> anyhow::Result<Network<AppTopic>> {
+    let resync_config = ResyncConfiguration::new().interval(10);
+    let sync_config = config
+        .sync_protocol
+        .configure(
+            vec![room.topic, LOBBY_TOPIC],
+            room.topic,
+            sync_failures_tx,
+            sync_outbox,
+            resync,
+            shutdown,
+        )
+        .resync(resync_config);
+    println!("sync protocol: {}", config.sync_protocol.name());
+
+    let network_id = room.network_id.unwrap_or_else(|| config.network_id());
+    let mut builder = NetworkBuilder::new(network_id)
+        .sync(sync_config)
+        .private_key(private_key.clone());
+
+    if config.mdns {
+        let interfaces = network_interfaces()
+            .into_iter()
+            .filter(|interface| !interface.loopback)
+            .map(|interface| format!("{} ({})", interface.name, interface.address))
+            .collect::<Vec<_>>();
+        println!(
+            "mdns binds to all interfaces, candidates: {}",
+            interfaces.join(", ")
+        );
+        builder = builder.discovery(LocalDiscovery::new());
+    }
+    if let Some(ref custom) = config.custom_discovery {
+        builder = custom.add_to(builder);
+    }
+
+    let discovery = discovery_mechanisms(config);
+    if discovery.is_empty() {
+        println!("no discovery mechanisms active, peers need to be added manually");
+    } else {
+        println!("active discovery mechanisms: {}", discovery.join(", "));
+    }
+
+    // Binding the endpoint, connecting to relays and starting discovery all happen in here.
+    let network = builder
+        .build()
+        .await
+        .with_context(|| format!("building network {}", hex::encode(network_id)))?;
+
+    Ok(network)
+}
+
+/// Names of the discovery mechanisms a network built with this config uses.
+fn discovery_mechanisms(config: &Config) -> Vec<&'static str> {
+    let mut discovery = Vec::new();
+    if config.mdns {
+        discovery.push("mdns");
+    }
+    if let Some(ref custom) = config.custom_discovery {
+        discovery.push(custom.name());
+    }
+    discovery
+}
@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use p2panda_core::PublicKey;
+use p2panda_net::SystemEvent;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::clock::now_millis;
+use crate::messages::peer_hue;
+use crate::AppTopic;
+
+/// Changes of the peer count are only reported after they settled for this long, so flapping
+/// connections don't cause the UI to flicker.
+pub const PEER_COUNT_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Once the peer cap is reached, a neighbour is only evicted for a new one when we haven't heard
+/// of it for this long.
+pub const EVICT_IDLE_AFTER: Duration = Duration::from_secs(15);
+
+/// How well we are connected to a neighbour.
+///
+/// p2panda-net doesn't expose round-trip times or whether a connection is direct or relayed, so
+/// this is derived from the messages the peer delivered to us.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerQuality {
+    pub public_key: PublicKey,
+    pub messages_received: u64,
+    /// Unix time in milliseconds of the last message delivered by the peer.
+    pub last_received_at: Option<u64>,
+}
+
+/// When we last heard of a peer, by any message it published including heartbeats.
+#[derive(Clone, Debug, Serialize)]
+pub struct Presence {
+    pub public_key: PublicKey,
+    /// Unix time in milliseconds.
+    pub last_seen_at: u64,
+    /// Whether the peer announced itself as a listener which never publishes triggers.
+    pub listener: bool,
+}
+
+/// Everything we know about a gossip neighbour.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerState {
+    pub public_key: PublicKey,
+    /// Color hue of the peer, see `peer_hue`.
+    pub hue: u16,
+    /// Unix time in milliseconds the peer became a neighbour.
+    pub joined_at: u64,
+    /// Unix time in milliseconds we last heard of the peer, `None` if it never published.
+    pub last_seen_at: Option<u64>,
+    pub messages_received: u64,
+    /// Whether the peer announced itself as a listener which never publishes triggers.
+    pub listener: bool,
+}
+
+/// Noteworthy change of the peers we know about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerChange {
+    /// A peer which wasn't discovered before.
+    Discovered(PublicKey),
+    /// A neighbour was evicted to make room for a new one.
+    Evicted(PublicKey),
+    /// A new neighbour was rejected because the cap is reached and all neighbours are active.
+    Rejected(PublicKey),
+}
+
+#[derive(Debug, Default)]
+struct PeerStats {
+    messages_received: u64,
+    last_received_at: Option<u64>,
+}
+
+/// Tracks the gossip neighbours we are currently connected to, and the peers we discovered.
+///
+/// Discovery finding a peer doesn't mean we manage to connect to it, peers which are discovered
+/// but never become neighbours hint at connectivity problems.
+///
+/// The number of neighbours can be capped. p2panda-net doesn't let us refuse gossip neighbours,
+/// instead neighbours beyond the cap are limited: messages they published are dropped once their
+/// signature is checked. Messages they relay from other authors still get through, gossip might
+/// not reach us on any other path.
+#[derive(Debug, Default)]
+pub struct Peers {
+    /// Neighbours with the time in milliseconds they became one.
+    neighbours: HashMap<PublicKey, u64>,
+    discovered: HashSet<PublicKey>,
+    /// Neighbours which were evicted or rejected due to the cap.
+    limited: HashSet<PublicKey>,
+    max_peers: Option<usize>,
+    last_discovered_at: Option<u64>,
+    discovery_timeout: Option<Duration>,
+    /// Point in time when we report that no peers were found, unless one appears until then.
+    no_peers_at: Option<Instant>,
+    stats: HashMap<PublicKey, PeerStats>,
+    last_seen: HashMap<PublicKey, u64>,
+    /// Peers whose most recent heartbeat announced them as listeners.
+    listeners: HashSet<PublicKey>,
+    reported_count: usize,
+    report_at: Option<Instant>,
+}
+
+impl Peers {
+    /// `discovery_timeout` is how long to wait for a peer to appear before reporting that none
+    /// were found, `None` never reports it.
+    pub fn new(max_peers: Option<usize>, discovery_timeout: Option<Duration>) -> Self {
+        let mut peers = Self {
+            max_peers,
+            discovery_timeout,
+            ..Default::default()
+        };
+        peers.arm_discovery_timeout();
+        peers
+    }
+
+    pub fn handle_event(&mut self, event: &SystemEvent<AppTopic>) -> Option<PeerChange> {
+        let mut change = None;
+
+        let peer_appeared = matches!(
+            event,
+            SystemEvent::GossipNeighborUp { .. } | SystemEvent::PeerDiscovered { .. }
+        );
+        if peer_appeared && self.no_peers_at.is_some() {
+            self.arm_discovery_timeout();
+        }
+
+        let changed = match event {
+            SystemEvent::GossipNeighborUp { peer, .. } => {
+                if self.neighbours.contains_key(peer) {
+                    return None;
+                }
+
+                if self.is_full() {
+                    match self.idle_neighbour() {
+                        Some(idle) => {
+                            self.evict(idle);
+                            change = Some(PeerChange::Evicted(idle));
+                        }
+                        None => {
+                            self.limited.insert(*peer);
+                            return Some(PeerChange::Rejected(*peer));
+                        }
+                    }
+                }
+
+                self.limited.remove(peer);
+                self.neighbours.insert(*peer, now_millis());
+                true
+            }
+            SystemEvent::GossipNeighborDown { peer, .. } => {
+                self.limited.remove(peer);
+                self.neighbours.remove(peer).is_some()
+            }
+            SystemEvent::PeerDiscovered { peer } => {
+                self.last_discovered_at = Some(now_millis());
+                return self
+                    .discovered
+                    .insert(*peer)
+                    .then_some(PeerChange::Discovered(*peer));
+            }
+            _ => false,
+        };
+
+        if changed {
+            self.schedule_report();
+        }
+
+        change
+    }
+
+    /// Changes the peer cap, `None` removes it. Returns the neighbours which were evicted to get
+    /// below a lowered cap, least recently active first.
+    pub fn set_max_peers(&mut self, max_peers: Option<usize>) -> Vec<PublicKey> {
+        self.max_peers = max_peers;
+
+        let mut evicted = Vec::new();
+        while self
+            .max_peers
+            .is_some_and(|max| self.neighbours.len() > max)
+        {
+            let Some(peer) = self.least_active() else {
+                break;
+            };
+            self.evict(peer);
+            evicted.push(peer);
+        }
+
+        if !evicted.is_empty() {
+            self.schedule_report();
+        }
+
+        evicted
+    }
+
+    /// Whether messages published by this peer should be dropped due to the cap.
+    pub fn is_limited(&self, peer: &PublicKey) -> bool {
+        self.limited.contains(peer)
+    }
+
+    /// Unix time in milliseconds discovery last reported a peer, including known ones.
+    pub fn last_discovered_at(&self) -> Option<u64> {
+        self.last_discovered_at
+    }
+
+    pub fn neighbours(&self) -> Vec<PublicKey> {
+        self.neighbours.keys().copied().collect()
+    }
+
+    /// Peers which were discovered but are not gossip neighbours.
+    pub fn discovered_only(&self) -> Vec<PublicKey> {
+        self.discovered
+            .iter()
+            .filter(|peer| !self.neighbours.contains_key(peer))
+            .copied()
+            .collect()
+    }
+
+    /// Counts a message which was delivered to us by the given peer.
+    pub fn record_message(&mut self, peer: PublicKey) {
+        let stats = self.stats.entry(peer).or_default();
+        stats.messages_received += 1;
+        stats.last_received_at = Some(now_millis());
+    }
+
+    /// Refreshes when we last heard of the peer who published a message.
+    pub fn seen(&mut self, public_key: PublicKey) {
+        self.last_seen.insert(public_key, now_millis());
+    }
+
+    /// Records whether a peer announced itself as a listener in its heartbeat.
+    pub fn set_listener(&mut self, public_key: PublicKey, listener: bool) {
+        if listener {
+            self.listeners.insert(public_key);
+        } else {
+            self.listeners.remove(&public_key);
+        }
+    }
+
+    pub fn presence(&self) -> Vec<Presence> {
+        self.last_seen
+            .iter()
+            .map(|(public_key, last_seen_at)| Presence {
+                public_key: *public_key,
+                last_seen_at: *last_seen_at,
+                listener: self.listeners.contains(public_key),
+            })
+            .collect()
+    }
+
+    /// Connection quality of all current neighbours.
+    pub fn quality(&self) -> Vec<PeerQuality> {
+        self.neighbours
+            .keys()
+            .map(|peer| {
+                let stats = self.stats.get(peer);
+                PeerQuality {
+                    public_key: *peer,
+                    messages_received: stats.map_or(0, |stats| stats.messages_received),
+                    last_received_at: stats.and_then(|stats| stats.last_received_at),
+                }
+            })
+            .collect()
+    }
+
+    /// State of all current neighbours.
+    pub fn states(&self) -> Vec<PeerState> {
+        self.neighbours
+            .iter()
+            .map(|(peer, joined_at)| PeerState {
+                public_key: *peer,
+                hue: peer_hue(peer),
+                joined_at: *joined_at,
+                last_seen_at: self.last_seen.get(peer).copied(),
+                messages_received: self
+                    .stats
+                    .get(peer)
+                    .map_or(0, |stats| stats.messages_received),
+                listener: self.listeners.contains(peer),
+            })
+            .collect()
+    }
+
+    /// Forget all neighbours, for example after the network was rebuilt.
+    pub fn clear(&mut self) {
+        self.neighbours.clear();
+        self.discovered.clear();
+        self.limited.clear();
+        self.schedule_report();
+        self.arm_discovery_timeout();
+    }
+
+    /// Point in time when the discovery timeout elapses.
+    pub fn no_peers_at(&self) -> Option<Instant> {
+        self.no_peers_at
+    }
+
+    /// Whether no peer was found when the discovery timeout elapsed, only true once per arming.
+    pub fn take_no_peers(&mut self) -> bool {
+        self.no_peers_at = None;
+        self.neighbours.is_empty() && self.discovered.is_empty()
+    }
+
+    fn arm_discovery_timeout(&mut self) {
+        self.no_peers_at = self
+            .discovery_timeout
+            .map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Point in time when the next peer count change is due to be reported.
+    pub fn report_at(&self) -> Option<Instant> {
+        self.report_at
+    }
+
+    /// Returns the current peer count if it differs from the last reported one.
+    pub fn take_count_change(&mut self) -> Option<usize> {
+        self.report_at = None;
+
+        let count = self.neighbours.len();
+        if count == self.reported_count {
+            return None;
+        }
+
+        self.reported_count = count;
+        Some(count)
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_peers
+            .is_some_and(|max| self.neighbours.len() >= max)
+    }
+
+    fn evict(&mut self, peer: PublicKey) {
+        self.neighbours.remove(&peer);
+        self.limited.insert(peer);
+    }
+
+    /// When we last heard of a neighbour, or when it became one if it didn't publish since.
+    fn last_active_at(&self, peer: &PublicKey) -> u64 {
+        let joined_at = self.neighbours.get(peer).copied().unwrap_or(0);
+        let last_seen_at = self.last_seen.get(peer).copied().unwrap_or(0);
+        joined_at.max(last_seen_at)
+    }
+
+    fn least_active(&self) -> Option<PublicKey> {
+        self.neighbours
+            .keys()
+            .min_by_key(|peer| self.last_active_at(peer))
+            .copied()
+    }
+
+    /// The least recently active neighbour, if we didn't hear of it for a while.
+    fn idle_neighbour(&self) -> Option<PublicKey> {
+        let cutoff = now_millis().saturating_sub(EVICT_IDLE_AFTER.as_millis() as u64);
+        self.least_active()
+            .filter(|peer| self.last_active_at(peer) < cutoff)
+    }
+
+    fn schedule_report(&mut self) {
+        self.report_at = Some(Instant::now() + PEER_COUNT_DEBOUNCE);
+    }
+}
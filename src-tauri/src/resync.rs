@@ -0,0 +1,52 @@
+use std::collections::HashSet;
+
+use p2panda_core::PublicKey;
+use p2panda_net::SystemEvent;
+
+use crate::messages::SyncState;
+use crate::AppTopic;
+
+/// Tells resync sessions apart from the initial sync with a peer.
+///
+/// The network's system events look the same for both, so we treat every session with a peer we
+/// already completed a sync with since the network was built as a resync.
+#[derive(Debug, Default)]
+pub struct Resyncs {
+    synced: HashSet<PublicKey>,
+    resyncing: HashSet<PublicKey>,
+}
+
+impl Resyncs {
+    /// Returns the peer and phase if the event belongs to a resync session.
+    pub fn handle_event(
+        &mut self,
+        event: &SystemEvent<AppTopic>,
+    ) -> Option<(PublicKey, SyncState)> {
+        match event {
+            SystemEvent::SyncStarted { peer, .. } => {
+                if !self.synced.contains(peer) {
+                    return None;
+                }
+                self.resyncing.insert(*peer);
+                Some((*peer, SyncState::Started))
+            }
+            SystemEvent::SyncDone { peer, .. } => {
+                self.synced.insert(*peer);
+                self.resyncing
+                    .remove(peer)
+                    .then_some((*peer, SyncState::Done))
+            }
+            SystemEvent::SyncFailed { peer, .. } => self
+                .resyncing
+                .remove(peer)
+                .then_some((*peer, SyncState::Failed)),
+            _ => None,
+        }
+    }
+
+    /// Forget all peers, for example after the network was rebuilt.
+    pub fn clear(&mut self) {
+        self.synced.clear();
+        self.resyncing.clear();
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use p2panda_core::PublicKey;
+use p2panda_net::{SystemEvent, TopicId};
+use tokio::time::Instant;
+
+use crate::room::LOBBY_TOPIC;
+use crate::AppTopic;
+
+/// Number of retries after a failed sync session before we give up on the peer.
+pub const MAX_SYNC_RETRIES: u32 = 5;
+
+/// Delay before the first retry, doubled with every further one.
+pub const SYNC_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryProgress {
+    /// A retry was scheduled after another failed session.
+    Scheduled {
+        peer: PublicKey,
+        topic_id: [u8; 32],
+        attempt: u32,
+        delay: Duration,
+    },
+
+    /// Syncing with a peer failed after all retries, nothing is retried until a session with it
+    /// succeeds again.
+    GaveUp {
+        peer: PublicKey,
+        topic_id: [u8; 32],
+        attempts: u32,
+    },
+}
+
+/// Retry which is due, the topic is to be synced again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DueRetry {
+    pub peer: PublicKey,
+    pub topic: AppTopic,
+    pub attempt: u32,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    /// When the next retry is due, `None` while a retry is running or after giving up.
+    retry_at: Option<Instant>,
+}
+
+/// Schedules retries with exponential backoff for failed sync sessions, per peer and topic.
+///
+/// The network doesn't allow initiating a sync session with a specific peer, a retry syncs the
+/// whole topic again, which includes the failed peer. Retrying stops after `MAX_SYNC_RETRIES`
+/// attempts, the periodic resync still runs on its own interval after that.
+///
+/// Sessions on the lobby aren't retried, it carries nothing to catch up on.
+#[derive(Debug, Default)]
+pub struct SyncRetries {
+    failures: HashMap<(PublicKey, AppTopic), Failures>,
+}
+
+impl SyncRetries {
+    pub fn handle_event(&mut self, event: &SystemEvent<AppTopic>) -> Option<RetryProgress> {
+        match event {
+            SystemEvent::SyncDone { peer, topic } => {
+                self.failures.remove(&(*peer, *topic));
+                None
+            }
+            SystemEvent::SyncFailed {
+                peer,
+                topic: Some(topic),
+            } if *topic != LOBBY_TOPIC => {
+                let failures = self.failures.entry((*peer, *topic)).or_insert(Failures {
+                    count: 0,
+                    retry_at: None,
+                });
+                if failures.count > MAX_SYNC_RETRIES {
+                    // Gave up already, further failures come from the periodic resync.
+                    return None;
+                }
+                failures.count += 1;
+
+                // The first failure is the original session, every further one a retry.
+                if failures.count > MAX_SYNC_RETRIES {
+                    failures.retry_at = None;
+                    return Some(RetryProgress::GaveUp {
+                        peer: *peer,
+                        topic_id: topic.id(),
+                        attempts: MAX_SYNC_RETRIES,
+                    });
+                }
+
+                let delay = SYNC_RETRY_BASE_DELAY * 2u32.pow(failures.count - 1);
+                failures.retry_at = Some(Instant::now() + delay);
+                Some(RetryProgress::Scheduled {
+                    peer: *peer,
+                    topic_id: topic.id(),
+                    attempt: failures.count,
+                    delay,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// When the next retry is due, `None` if none is scheduled.
+    pub fn next_retry_at(&self) -> Option<Instant> {
+        self.failures
+            .values()
+            .filter_map(|failures| failures.retry_at)
+            .min()
+    }
+
+    /// Retries which are due now, they aren't returned again.
+    pub fn take_due(&mut self) -> Vec<DueRetry> {
+        let now = Instant::now();
+        self.failures
+            .iter_mut()
+            .filter(|(_, failures)| failures.retry_at.is_some_and(|at| at <= now))
+            .map(|(&(peer, topic), failures)| {
+                failures.retry_at = None;
+                DueRetry {
+                    peer,
+                    topic,
+                    attempt: failures.count,
+                }
+            })
+            .collect()
+    }
+
+    /// Forgets all failures, for example when the topics changed.
+    pub fn clear(&mut self) {
+        self.failures.clear();
+    }
+}
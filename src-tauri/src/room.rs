@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use p2panda_core::Hash;
+use p2panda_net::TopicId;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::now_millis;
+use crate::encryption::{RoomKey, RoomSecret};
+use crate::{AppTopic, APP_TOPIC};
+
+/// Well-known topic every node subscribes to, to advertise the room it is in.
+pub static LOBBY_TOPIC: AppTopic = AppTopic([2; 32]);
+
+/// How often we advertise our room in the lobby.
+pub const ROOM_ADVERTISE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Rooms which weren't advertised for this long are considered gone.
+pub const ROOM_TTL: Duration = Duration::from_secs(30);
+
+/// Context the topic of a room joined with a secret is derived from the secret in.
+const TOPIC_CONTEXT: &str = "can-you-peer-me v1 room topic";
+
+/// Context the id of a room's dedicated network is derived from its secret in.
+const NETWORK_ID_CONTEXT: &str = "can-you-peer-me v1 room network id";
+
+/// Version of the format the last joined room is stored in.
+const SAVED_ROOM_VERSION: u16 = 1;
+
+/// Topic and network a node is joined to.
+///
+/// Rooms are distinct topics on the shared network, peers in different rooms never see each
+/// other's messages. Rooms joined with a secret additionally build their own network with an id
+/// derived from it, so nodes outside the room don't even discover them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Room {
+    pub topic: AppTopic,
+    /// Id of the room's dedicated network, `None` for rooms on the shared network.
+    pub network_id: Option<[u8; 32]>,
+    /// Name the room is advertised with in the lobby, `None` for private rooms and the default
+    /// room, which are never advertised.
+    pub name: Option<String>,
+    /// Key messages on the room's topic are encrypted with, `None` sends them in plaintext.
+    #[serde(default)]
+    pub encryption_key: Option<RoomKey>,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Self {
+            topic: APP_TOPIC,
+            network_id: None,
+            name: None,
+            encryption_key: None,
+        }
+    }
+}
+
+impl Room {
+    /// Room with the given name, private to everyone knowing the secret if there is one.
+    ///
+    /// Without a secret the topic is derived from the name alone, anyone can join by name. With
+    /// one, topic and network id are derived from the secret, the name only tells rooms sharing
+    /// a secret apart.
+    pub fn new(name: &str, secret: Option<&RoomSecret>) -> Self {
+        let topic = match secret {
+            Some(secret) => {
+                *blake3::keyed_hash(&secret.derive_key(TOPIC_CONTEXT), name.as_bytes()).as_bytes()
+            }
+            None => *Hash::new(format!("room:{name}").as_bytes()).as_bytes(),
+        };
+        Self {
+            topic: AppTopic(topic),
+            network_id: secret.map(|secret| secret.derive_key(NETWORK_ID_CONTEXT)),
+            name: secret.is_none().then(|| name.to_string()),
+            encryption_key: None,
+        }
+    }
+
+    /// Encrypts the room's messages with a key derived from the secret.
+    pub fn encrypted(mut self, secret: &RoomSecret) -> Self {
+        self.encryption_key = Some(RoomKey::from_secret(secret));
+        self
+    }
+
+    /// Private room with its own network, both derived from a single passphrase, and encrypted
+    /// with a key derived from it as well if `encrypt` is set.
+    pub fn from_passphrase(passphrase: &str, encrypt: bool) -> Self {
+        let secret = RoomSecret::new(passphrase);
+        let room = Self::new(passphrase, Some(&secret));
+        if encrypt {
+            room.encrypted(&secret)
+        } else {
+            room
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRoom {
+    version: u16,
+    room: Room,
+}
+
+/// Remembers the last joined room across restarts.
+///
+/// The secret or passphrase itself is never stored, but the derived topic, network id and
+/// encryption key are just as good for joining the room and reading its messages. On unix the
+/// file is only readable by the current user, elsewhere it relies on the permissions of the
+/// config directory.
+#[derive(Clone, Debug)]
+pub struct RoomStore {
+    path: PathBuf,
+}
+
+impl RoomStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            path: dir.join("room.json"),
+        }
+    }
+
+    /// The last joined room, or the default room if none was stored or it can't be read.
+    pub fn load(&self) -> Room {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Room::default(),
+            Err(err) => {
+                println!("failed to read saved room, using the default room: {err}");
+                return Room::default();
+            }
+        };
+
+        match serde_json::from_slice::<SavedRoom>(&bytes) {
+            Ok(saved) if saved.version == SAVED_ROOM_VERSION => saved.room,
+            Ok(saved) => {
+                println!(
+                    "saved room has incompatible version {}, using the default room",
+                    saved.version
+                );
+                Room::default()
+            }
+            Err(err) => {
+                println!("failed to parse saved room, using the default room: {err}");
+                Room::default()
+            }
+        }
+    }
+
+    pub fn save(&self, room: &Room) -> anyhow::Result<()> {
+        let saved = SavedRoom {
+            version: SAVED_ROOM_VERSION,
+            room: room.clone(),
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context("creating config directory")?;
+        }
+        write_private(&self.path, &serde_json::to_vec(&saved)?).context("writing saved room")?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).context("removing saved room")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes `bytes` to `path`, on unix the file is made readable and writable by its owner only.
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+
+    // The mode only applies to new files, rooms saved by earlier versions are readable by all.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(bytes)
+}
+
+/// Room which was recently advertised in the lobby.
+#[derive(Clone, Debug, Serialize)]
+pub struct RoomInfo {
+    /// Hex-encoded topic id of the room.
+    pub topic: String,
+    pub name: String,
+    /// Unix time in milliseconds.
+    pub last_seen_at: u64,
+}
+
+/// Rooms advertised by nodes nearby, stale ones age out after `ROOM_TTL`.
+#[derive(Debug, Default)]
+pub struct Lobby {
+    rooms: HashMap<AppTopic, (String, u64)>,
+}
+
+impl Lobby {
+    pub fn seen(&mut self, topic: AppTopic, name: String) {
+        self.prune();
+        self.rooms.insert(topic, (name, now_millis()));
+    }
+
+    /// Recently advertised rooms, most recently seen first.
+    pub fn rooms(&mut self) -> Vec<RoomInfo> {
+        self.prune();
+
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|(topic, (name, last_seen_at))| RoomInfo {
+                topic: hex::encode(topic.id()),
+                name: name.clone(),
+                last_seen_at: *last_seen_at,
+            })
+            .collect();
+        rooms.sort_by(|a, b| b.last_seen_at.cmp(&a.last_seen_at));
+        rooms
+    }
+
+    fn prune(&mut self) {
+        let now = now_millis();
+        let ttl = ROOM_TTL.as_millis() as u64;
+        self.rooms
+            .retain(|_, (_, last_seen_at)| now.saturating_sub(*last_seen_at) <= ttl);
+    }
+}
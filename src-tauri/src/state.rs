@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use p2panda_core::PublicKey;
+use p2panda_net::TopicId;
+use serde::ser::SerializeStruct;
+use serde::Serialize;
+
+use crate::messages::ApplicationMessage;
+use crate::metrics::{MessageRate, QueueDepth};
+use crate::peers::PeerState;
+use crate::AppTopic;
+
+/// Everything the node currently knows, for debugging or restoring a frontend from scratch.
+#[derive(Clone, Debug)]
+pub struct AppStateSnapshot {
+    pub public_key: PublicKey,
+    pub topic: AppTopic,
+    /// Whether we joined the gossip overlay of each topic we are subscribed to, by topic id.
+    pub topics_ready: HashMap<[u8; 32], bool>,
+    /// Name of the room we are in, `None` for the default room and secret ones.
+    pub room_name: Option<String>,
+    /// Current gossip neighbours.
+    pub peers: Vec<PeerState>,
+    pub recent_triggers: Vec<ApplicationMessage>,
+    pub message_rate: MessageRate,
+    pub queue_depth: QueueDepth,
+}
+
+impl Serialize for AppStateSnapshot {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let topics_ready: HashMap<String, bool> = self
+            .topics_ready
+            .iter()
+            .map(|(topic_id, ready)| (hex::encode(topic_id), *ready))
+            .collect();
+
+        let mut state = serializer.serialize_struct("AppStateSnapshot", 8)?;
+        state.serialize_field("public_key", &self.public_key)?;
+        state.serialize_field("topic", &hex::encode(self.topic.id()))?;
+        state.serialize_field("topics_ready", &topics_ready)?;
+        state.serialize_field("room_name", &self.room_name)?;
+        state.serialize_field("peers", &self.peers)?;
+        state.serialize_field("recent_triggers", &self.recent_triggers)?;
+        state.serialize_field("message_rate", &self.message_rate)?;
+        state.serialize_field("queue_depth", &self.queue_depth)?;
+        state.end()
+    }
+}
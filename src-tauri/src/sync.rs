@@ -1,25 +1,304 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
 use futures_lite::{AsyncRead, AsyncWrite, StreamExt};
 use futures_util::{Sink, SinkExt};
+use p2panda_net::SyncConfiguration;
 use p2panda_sync::cbor::{into_cbor_sink, into_cbor_stream};
 use p2panda_sync::{FromSync, SyncError, SyncProtocol};
 use serde::{Deserialize, Serialize};
-use tokio::time::sleep;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
 
 use crate::AppTopic;
 
+/// Version of the sync message format we speak.
+const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest version of the sync message format we are still compatible with.
+const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Peer sends and accepts `Data` messages during a sync session.
+const CAPABILITY_DATA: u32 = 1;
+
+/// Bit flags of optional protocol features we support.
+const CAPABILITIES: u32 = CAPABILITY_DATA;
+
+/// Maximum number of messages kept for delivery via sync, oldest are dropped.
+pub const SYNC_OUTBOX_CAPACITY: usize = 256;
+
+/// How long messages are delivered via sync, long enough for several resync rounds with every
+/// peer we are connected to.
+pub const SYNC_OUTBOX_TTL: Duration = Duration::from_secs(120);
+
 #[derive(Debug, Serialize, Deserialize)]
 enum SyncMessage {
+    Hello { version: u16, capabilities: u32 },
     TopicQuery(AppTopic),
+    Data(Vec<u8>),
     Done,
 }
 
-/// A sync implementation which fulfills basic protocol requirements but nothing more
+/// Features both peers agreed on during the `Hello` handshake of a sync session.
+///
+/// Only one version of the message format exists so far, optional features are gated on
+/// capabilities instead.
+#[derive(Clone, Copy, Debug)]
+struct Negotiated {
+    capabilities: u32,
+}
+
+impl Negotiated {
+    /// Picks the capabilities both sides support, rejecting peers which speak a version we are
+    /// incompatible with.
+    fn new(version: u16, capabilities: u32) -> Result<Self, SyncError> {
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(SyncError::UnexpectedBehaviour(format!(
+                "incompatible sync protocol version {version}, expected at least \
+                 {MIN_PROTOCOL_VERSION}"
+            )));
+        }
+
+        Ok(Self {
+            capabilities: capabilities & CAPABILITIES,
+        })
+    }
+
+    fn supports_data(&self) -> bool {
+        self.capabilities & CAPABILITY_DATA != 0
+    }
+}
+
+/// Message every sync session starts with on both sides.
+fn hello() -> SyncMessage {
+    SyncMessage::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES,
+    }
+}
+
+/// Encoded messages which are delivered to every peer we sync with instead of via gossip.
+///
+/// Sync sessions are repeated on every resync, so the same messages arrive several times and
+/// receivers need to deduplicate them. Sessions don't tell which peer we sync with, messages
+/// can't be acknowledged per peer, instead they expire after `SYNC_OUTBOX_TTL`. Shared between
+/// rebuilds of the network so messages survive reconnects.
+#[derive(Clone, Debug, Default)]
+pub struct SyncOutbox {
+    /// Encoded messages with the time they were pushed, oldest first.
+    messages: Arc<Mutex<VecDeque<(Instant, Vec<u8>)>>>,
+}
+
+impl SyncOutbox {
+    pub async fn push(&self, bytes: Vec<u8>) {
+        let mut messages = self.messages.lock().await;
+        if messages.len() == SYNC_OUTBOX_CAPACITY {
+            messages.pop_front();
+        }
+        messages.push_back((Instant::now(), bytes));
+    }
+
+    pub async fn clear(&self) {
+        self.messages.lock().await.clear();
+    }
+
+    /// Messages which didn't expire yet, expired ones are dropped.
+    async fn snapshot(&self) -> Vec<Vec<u8>> {
+        let mut messages = self.messages.lock().await;
+        while messages
+            .front()
+            .is_some_and(|(pushed_at, _)| pushed_at.elapsed() >= SYNC_OUTBOX_TTL)
+        {
+            messages.pop_front();
+        }
+        messages.iter().map(|(_, bytes)| bytes.clone()).collect()
+    }
+}
+
+/// Sends all messages of the outbox, if the peer can handle them.
+async fn send_outbox<S>(
+    sink: &mut S,
+    outbox: &SyncOutbox,
+    negotiated: Negotiated,
+) -> Result<(), SyncError>
+where
+    S: Sink<SyncMessage, Error = SyncError> + Send + Unpin,
+{
+    if !negotiated.supports_data() {
+        return Ok(());
+    }
+
+    for bytes in outbox.snapshot().await {
+        sink.send(SyncMessage::Data(bytes)).await?;
+    }
+
+    Ok(())
+}
+
+/// Whether sync sessions we initiate pull in the other side's messages.
+///
+/// Shared between rebuilds of the network, so it can be toggled without rebuilding it.
+#[derive(Clone, Debug)]
+pub struct ResyncSwitch {
+    enabled: Arc<AtomicBool>,
+}
+
+impl Default for ResyncSwitch {
+    fn default() -> Self {
+        Self {
+            enabled: Arc::new(AtomicBool::new(true)),
+        }
+    }
+}
+
+impl ResyncSwitch {
+    pub fn set(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+/// Reason why a sync session failed on our side.
+///
+/// The `SyncFailed` system event emitted by the network doesn't carry any reason, so we report
+/// the errors returned by our own protocol implementation separately.
+#[derive(Clone, Debug)]
+pub struct SyncFailure {
+    pub topic: Option<AppTopic>,
+    pub reason: String,
+}
+
+impl fmt::Display for SyncFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.topic {
+            Some(topic) => write!(f, "{} (topic {:?})", self.reason, topic),
+            None => write!(f, "{}", self.reason),
+        }
+    }
+}
+
+/// Sync protocols the network can be built with, picked by name.
+///
+/// New protocols are added as a variant here, so they can be compared against the existing ones
+/// at runtime without touching how the network is built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncProtocolKind {
+    #[default]
+    Dummy,
+}
+
+impl SyncProtocolKind {
+    /// Names of all registered protocols.
+    pub const NAMES: &'static [&'static str] = &["dummy"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dummy => "dummy",
+        }
+    }
+
+    /// Sync configuration running this protocol for the given topics, the outbox is only sent in
+    /// sessions for `outbox_topic`.
+    pub fn configure(
+        &self,
+        topics: Vec<AppTopic>,
+        outbox_topic: AppTopic,
+        failures_tx: mpsc::Sender<SyncFailure>,
+        outbox: SyncOutbox,
+        resync: ResyncSwitch,
+        shutdown: CancellationToken,
+    ) -> SyncConfiguration<AppTopic> {
+        match self {
+            Self::Dummy => SyncConfiguration::new(DummyProtocol::new(
+                topics,
+                outbox_topic,
+                failures_tx,
+                outbox,
+                resync,
+                shutdown,
+            )),
+        }
+    }
+}
+
+impl FromStr for SyncProtocolKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "dummy" => Ok(Self::Dummy),
+            _ => Err(format!(
+                "unknown sync protocol {name}, expected one of: {}",
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// A sync implementation which fulfills basic protocol requirements and exchanges the messages
+/// of both sides' outboxes, but nothing more
 #[derive(Debug)]
-pub struct DummyProtocol {}
+pub struct DummyProtocol {
+    /// Topics the network is subscribed to, sessions for any other topic are rejected.
+    topics: Vec<AppTopic>,
+    /// Topic of the room the outbox belongs to, sessions for other topics like the lobby never
+    /// carry it, as peers outside the room take part in them.
+    outbox_topic: AppTopic,
+    failures_tx: mpsc::Sender<SyncFailure>,
+    outbox: SyncOutbox,
+    resync: ResyncSwitch,
+    /// Cancelled when the network this protocol belongs to shuts down.
+    shutdown: CancellationToken,
+}
+
+impl DummyProtocol {
+    pub fn new(
+        topics: Vec<AppTopic>,
+        outbox_topic: AppTopic,
+        failures_tx: mpsc::Sender<SyncFailure>,
+        outbox: SyncOutbox,
+        resync: ResyncSwitch,
+        shutdown: CancellationToken,
+    ) -> Self {
+        Self {
+            topics,
+            outbox_topic,
+            failures_tx,
+            outbox,
+            resync,
+            shutdown,
+        }
+    }
+
+    fn report(
+        &self,
+        topic: Option<AppTopic>,
+        result: Result<(), SyncError>,
+    ) -> Result<(), SyncError> {
+        if let Err(ref err) = result {
+            // Sessions aborted because we are shutting down didn't fail.
+            if self.shutdown.is_cancelled() {
+                return result;
+            }
+
+            // Reporting is best-effort, we don't want to hold up the sync session for it.
+            let _ = self.failures_tx.try_send(SyncFailure {
+                topic,
+                reason: err.to_string(),
+            });
+        }
+        result
+    }
+}
 
 #[async_trait]
 impl<'a> SyncProtocol<'a, AppTopic> for DummyProtocol {
@@ -34,30 +313,79 @@ impl<'a> SyncProtocol<'a, AppTopic> for DummyProtocol {
         rx: Box<&'a mut (dyn AsyncRead + Send + Unpin)>,
         mut app_tx: Box<&'a mut (dyn Sink<FromSync<AppTopic>, Error = SyncError> + Send + Unpin)>,
     ) -> Result<(), SyncError> {
-        let mut sink = into_cbor_sink(tx);
-        let mut stream = into_cbor_stream(rx);
+        let result = async {
+            let mut sink = into_cbor_sink(tx);
+            let mut stream = into_cbor_stream(rx);
 
-        sink.send(SyncMessage::TopicQuery(topic_query.clone()))
-            .await?;
+            sink.send(hello()).await?;
 
-        // Wait a few seconds to simulate some very intensive sync process.
-        sleep(Duration::from_secs(3)).await;
+            let Some(result) = stream.next().await else {
+                return Err(SyncError::UnexpectedStreamClosure);
+            };
+            let message: SyncMessage = result?;
+            let SyncMessage::Hello {
+                version,
+                capabilities,
+            } = message
+            else {
+                return Err(SyncError::UnexpectedBehaviour(
+                    "expected hello message".to_string(),
+                ));
+            };
+            let negotiated = Negotiated::new(version, capabilities)?;
 
-        sink.send(SyncMessage::Done).await?;
-        app_tx.send(FromSync::HandshakeSuccess(topic_query)).await?;
+            sink.send(SyncMessage::TopicQuery(topic_query.clone()))
+                .await?;
 
-        while let Some(result) = stream.next().await {
-            let message: SyncMessage = result?;
-            match &message {
-                SyncMessage::TopicQuery(_) => panic!(),
-                SyncMessage::Done => break,
+            // While resync is paused we still complete the session, so the peer doesn't count it
+            // as failed, but skip the work and ignore everything it sends us.
+            let enabled = self.resync.is_enabled();
+            if enabled {
+                // Wait a few seconds to simulate some very intensive sync process, unless the
+                // network shuts down in the meantime.
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(3)) => (),
+                    _ = self.shutdown.cancelled() => {
+                        return Err(SyncError::Critical("sync cancelled on shutdown".to_string()));
+                    }
+                }
+
+                if topic_query == self.outbox_topic {
+                    send_outbox(&mut sink, &self.outbox, negotiated).await?;
+                }
             }
-        }
+            sink.send(SyncMessage::Done).await?;
+            app_tx.send(FromSync::HandshakeSuccess(topic_query)).await?;
 
-        sink.flush().await?;
-        app_tx.flush().await?;
+            while let Some(result) = stream.next().await {
+                let message: SyncMessage = result?;
+                match message {
+                    SyncMessage::Hello { .. } | SyncMessage::TopicQuery(_) => {
+                        return Err(SyncError::UnexpectedBehaviour(format!(
+                            "unexpected message {message:?}"
+                        )))
+                    }
+                    SyncMessage::Data(_) if !enabled => (),
+                    SyncMessage::Data(bytes) => {
+                        app_tx
+                            .send(FromSync::Data {
+                                header: bytes,
+                                payload: None,
+                            })
+                            .await?
+                    }
+                    SyncMessage::Done => break,
+                }
+            }
+
+            sink.flush().await?;
+            app_tx.flush().await?;
 
-        Ok(())
+            Ok::<(), SyncError>(())
+        }
+        .await;
+
+        self.report(Some(topic_query), result)
     }
 
     async fn accept(
@@ -66,26 +394,74 @@ impl<'a> SyncProtocol<'a, AppTopic> for DummyProtocol {
         rx: Box<&'a mut (dyn AsyncRead + Send + Unpin)>,
         mut app_tx: Box<&'a mut (dyn Sink<FromSync<AppTopic>, Error = SyncError> + Send + Unpin)>,
     ) -> Result<(), SyncError> {
-        let mut sink = into_cbor_sink(tx);
-        let mut stream = into_cbor_stream(rx);
+        let mut topic = None;
+
+        let result = async {
+            let mut sink = into_cbor_sink(tx);
+            let mut stream = into_cbor_stream(rx);
 
-        while let Some(result) = stream.next().await {
+            let Some(result) = stream.next().await else {
+                return Err(SyncError::UnexpectedStreamClosure);
+            };
             let message: SyncMessage = result?;
-            match &message {
-                SyncMessage::TopicQuery(topic_query) => {
-                    app_tx
-                        .send(FromSync::HandshakeSuccess(topic_query.clone()))
-                        .await?
+            let SyncMessage::Hello {
+                version,
+                capabilities,
+            } = message
+            else {
+                return Err(SyncError::UnexpectedBehaviour(
+                    "expected hello message".to_string(),
+                ));
+            };
+            let negotiated = Negotiated::new(version, capabilities)?;
+
+            sink.send(hello()).await?;
+
+            // While resync is paused we skip what the initiator pushes to us as well, but still
+            // hand out our own outbox.
+            let enabled = self.resync.is_enabled();
+            while let Some(result) = stream.next().await {
+                let message: SyncMessage = result?;
+                match message {
+                    SyncMessage::Hello { .. } => {
+                        return Err(SyncError::UnexpectedBehaviour(
+                            "unexpected hello message".to_string(),
+                        ))
+                    }
+                    SyncMessage::TopicQuery(topic_query) => {
+                        topic = Some(topic_query);
+                        if !self.topics.contains(&topic_query) {
+                            return Err(SyncError::UnexpectedBehaviour(format!(
+                                "sync requested for topic {topic_query:?} we are not subscribed to"
+                            )));
+                        }
+                        app_tx.send(FromSync::HandshakeSuccess(topic_query)).await?
+                    }
+                    SyncMessage::Data(_) if !enabled => (),
+                    SyncMessage::Data(bytes) => {
+                        app_tx
+                            .send(FromSync::Data {
+                                header: bytes,
+                                payload: None,
+                            })
+                            .await?
+                    }
+                    SyncMessage::Done => break,
                 }
-                SyncMessage::Done => break,
             }
-        }
 
-        sink.send(SyncMessage::Done).await?;
+            if topic == Some(self.outbox_topic) {
+                send_outbox(&mut sink, &self.outbox, negotiated).await?;
+            }
+            sink.send(SyncMessage::Done).await?;
+
+            sink.flush().await?;
+            app_tx.flush().await?;
 
-        sink.flush().await?;
-        app_tx.flush().await?;
+            Ok::<(), SyncError>(())
+        }
+        .await;
 
-        Ok(())
+        self.report(topic, result)
     }
 }
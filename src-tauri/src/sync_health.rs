@@ -0,0 +1,94 @@
+use std::collections::{HashMap, VecDeque};
+
+use p2panda_core::PublicKey;
+use p2panda_net::SystemEvent;
+use serde::Serialize;
+
+use crate::clock::now_millis;
+use crate::messages::SyncState;
+use crate::AppTopic;
+
+/// Number of recent sync events kept per peer, older ones are dropped.
+pub const SYNC_HEALTH_HISTORY: usize = 32;
+
+/// Phase a sync session with a peer reached, and when.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SyncOutcome {
+    pub state: SyncState,
+    /// Unix time in milliseconds.
+    pub at: u64,
+}
+
+/// How reliably syncing with a peer works.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerSyncHealth {
+    pub public_key: PublicKey,
+    /// Number of sessions which started, completed and failed since the node started.
+    pub started: u64,
+    pub done: u64,
+    pub failed: u64,
+    /// Whether the most recently finished session completed or failed, `None` if none did yet.
+    pub last_outcome: Option<SyncOutcome>,
+    /// Most recent sync events, oldest first.
+    pub recent: Vec<SyncOutcome>,
+}
+
+#[derive(Debug, Default)]
+struct PeerHistory {
+    started: u64,
+    done: u64,
+    failed: u64,
+    last_outcome: Option<SyncOutcome>,
+    recent: VecDeque<SyncOutcome>,
+}
+
+/// Records the outcome of sync sessions per peer, across rebuilds of the network.
+#[derive(Debug, Default)]
+pub struct SyncHealth {
+    peers: HashMap<PublicKey, PeerHistory>,
+}
+
+impl SyncHealth {
+    pub fn handle_event(&mut self, event: &SystemEvent<AppTopic>) {
+        let (peer, state) = match event {
+            SystemEvent::SyncStarted { peer, .. } => (peer, SyncState::Started),
+            SystemEvent::SyncDone { peer, .. } => (peer, SyncState::Done),
+            SystemEvent::SyncFailed { peer, .. } => (peer, SyncState::Failed),
+            _ => return,
+        };
+
+        let history = self.peers.entry(*peer).or_default();
+        let outcome = SyncOutcome {
+            state,
+            at: now_millis(),
+        };
+        match state {
+            SyncState::Started => history.started += 1,
+            SyncState::Done => history.done += 1,
+            SyncState::Failed => history.failed += 1,
+        }
+        if !matches!(state, SyncState::Started) {
+            history.last_outcome = Some(outcome);
+        }
+
+        if history.recent.len() == SYNC_HEALTH_HISTORY {
+            history.recent.pop_front();
+        }
+        history.recent.push_back(outcome);
+    }
+
+    /// Sync health of every peer we ever synced with.
+    pub fn peers(&self) -> Vec<PeerSyncHealth> {
+        self.peers
+            .iter()
+            .map(|(public_key, history)| PeerSyncHealth {
+                public_key: *public_key,
+                started: history.started,
+                done: history.done,
+                failed: history.failed,
+                last_outcome: history.last_outcome,
+                recent: history.recent.iter().copied().collect(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,57 @@
+use tokio::time::Instant;
+
+/// Sustained number of messages per second we allow to be published by default.
+pub const DEFAULT_PUBLISH_RATE: f64 = 50.0;
+
+/// Number of messages which can be published in a quick burst by default.
+pub const DEFAULT_PUBLISH_BURST: u32 = 100;
+
+/// Token bucket limiting how fast messages are published.
+///
+/// Every message takes a token, tokens refill continuously at `rate` per second up to `burst`.
+/// The defaults are generous enough for fast drumming on several pads at once, while a held-down
+/// pad or a runaway UI can't flood the gossip overlay.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: u32,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_PUBLISH_RATE, DEFAULT_PUBLISH_BURST)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Changes the rate and burst size, the bucket starts out full again.
+    pub fn configure(&mut self, rate: f64, burst: u32) {
+        *self = Self::new(rate, burst);
+    }
+
+    /// Takes a token, returns `false` if the bucket is empty.
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst as f64);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
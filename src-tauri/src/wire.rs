@@ -0,0 +1,394 @@
+use p2panda_core::cbor::{decode_cbor, encode_cbor, DecodeError};
+use p2panda_core::{PrivateKey, PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::compression::{self, CompressionError};
+use crate::encryption::{self, RoomKey};
+use crate::messages::ControlKind;
+use crate::AppTopic;
+
+/// Reasons why a received message couldn't be decoded.
+#[derive(Debug, Error)]
+pub enum WireError {
+    #[error(transparent)]
+    Compression(#[from] CompressionError),
+
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// Application payloads published on the topic.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Payload {
+    Trigger {
+        timestamp: u64,
+        sample_index: u16,
+        #[serde(default)]
+        seq: u64,
+        /// Sound bank the sample index refers to, peers which predate banks all use bank 0.
+        #[serde(default)]
+        bank_id: u16,
+    },
+    /// Trigger resent from someone's history, as encoded and signed by its author.
+    ///
+    /// Receivers keep its original timestamp and don't play it live. Anyone holding the
+    /// original message can replay it, the signature on the replay only proves who resent it.
+    Replay {
+        #[serde(with = "byte_string")]
+        message: Vec<u8>,
+    },
+    Control(ControlKind),
+    /// Arbitrary application bytes, passed to the frontend without being interpreted.
+    Raw(Vec<u8>),
+    /// Asks `peer` to resend its triggers with sequence numbers from `from` to `to`.
+    RangeRequest {
+        peer: PublicKey,
+        from: u64,
+        to: u64,
+    },
+    /// Announces that we are still around while no other messages are flowing.
+    Heartbeat {
+        timestamp: u64,
+        /// Set by listeners, so performers know who is only watching.
+        #[serde(default)]
+        listener: bool,
+    },
+    /// Advertises the room we are in, only sent on the lobby topic.
+    RoomAdvertisement {
+        topic: AppTopic,
+        name: String,
+        timestamp: u64,
+    },
+    /// Tells `requester` that the requested range was evicted from our history already.
+    RangeUnavailable {
+        requester: PublicKey,
+        from: u64,
+        to: u64,
+    },
+}
+
+/// CBOR header of an array with two elements.
+const CBOR_PAIR: u8 = 0x82;
+
+/// Payload sent over the network, signed by the peer who published it.
+///
+/// The payload is kept encoded exactly as it was signed. Peers running another version encode
+/// a different set of fields, re-encoding the decoded payload wouldn't reproduce their bytes.
+#[derive(Debug)]
+pub struct SignedPayload {
+    pub public_key: PublicKey,
+    pub payload: Payload,
+    payload_bytes: Vec<u8>,
+    signature: Signature,
+}
+
+impl SignedPayload {
+    pub fn new(private_key: &PrivateKey, payload: Payload) -> Self {
+        let payload_bytes = encode_cbor(&payload).expect("encode payload");
+        Self::sign(private_key, payload, payload_bytes)
+    }
+
+    fn sign(private_key: &PrivateKey, payload: Payload, payload_bytes: Vec<u8>) -> Self {
+        let public_key = private_key.public_key();
+        let signature = private_key.sign(&Self::signed_bytes(&public_key, &payload_bytes));
+
+        Self {
+            public_key,
+            payload,
+            payload_bytes,
+            signature,
+        }
+    }
+
+    /// Whether the payload was signed by the private key belonging to `public_key`.
+    pub fn verify(&self) -> bool {
+        // The decoded payload must still be the one which was signed.
+        let signed_payload: Result<Payload, _> = decode_cbor(&self.payload_bytes[..]);
+        if signed_payload.ok().as_ref() != Some(&self.payload) {
+            return false;
+        }
+
+        let bytes = Self::signed_bytes(&self.public_key, &self.payload_bytes);
+        self.public_key.verify(&bytes, &self.signature)
+    }
+
+    /// Encoding of `(public_key, payload)`, with the payload bytes spliced in as they are.
+    fn signed_bytes(public_key: &PublicKey, payload_bytes: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![CBOR_PAIR];
+        bytes.extend(encode_cbor(public_key).expect("encode public key"));
+        bytes.extend_from_slice(payload_bytes);
+        bytes
+    }
+}
+
+/// Version of the envelope format we publish, bumped whenever a released format changes.
+pub const ENVELOPE_VERSION: u16 = 1;
+
+/// Versioned wrapper around everything published on the topic.
+///
+/// The version tells receivers which format they are decoding. Unknown fields are ignored while
+/// decoding, so new fields can be added to payloads without breaking older peers, as long as they
+/// come with defaults for messages which don't carry them. Signatures cover the payload bytes as
+/// sent, so they stay valid no matter which fields the receiver knows.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    version: u16,
+    message: SignedBytes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedBytes {
+    public_key: PublicKey,
+    #[serde(with = "byte_string")]
+    payload: Vec<u8>,
+    signature: Signature,
+}
+
+/// Signs, encodes and compresses a payload to be sent on the topic.
+pub fn encode_payload(private_key: &PrivateKey, payload: Payload) -> Vec<u8> {
+    encode_signed(SignedPayload::new(private_key, payload))
+}
+
+/// Signs, encodes and, if the room requires it, encrypts a payload for the room's topic.
+pub fn encode_for_room(
+    private_key: &PrivateKey,
+    key: Option<&RoomKey>,
+    payload: Payload,
+) -> Vec<u8> {
+    encryption::seal(key, encode_payload(private_key, payload))
+}
+
+/// Encodes and compresses an already signed payload to be sent on the topic.
+pub fn encode_signed(signed: SignedPayload) -> Vec<u8> {
+    compression::compress(envelope_bytes(signed))
+}
+
+/// Signs and encodes a payload without compressing it, receivers limit the size of this.
+pub fn encode_envelope(private_key: &PrivateKey, payload: Payload) -> Vec<u8> {
+    envelope_bytes(SignedPayload::new(private_key, payload))
+}
+
+fn envelope_bytes(signed: SignedPayload) -> Vec<u8> {
+    let envelope = Envelope {
+        version: ENVELOPE_VERSION,
+        message: SignedBytes {
+            public_key: signed.public_key,
+            payload: signed.payload_bytes,
+            signature: signed.signature,
+        },
+    };
+    encode_cbor(&envelope).expect("encode message")
+}
+
+/// Decompresses and decodes a message received on the topic.
+///
+/// Messages larger than `limit` bytes once decompressed are rejected. The signature is not
+/// checked, see `SignedPayload::verify`.
+pub fn decode_payload(bytes: &[u8], limit: usize) -> Result<SignedPayload, WireError> {
+    let bytes = &compression::decompress(bytes, limit)?[..];
+    let Envelope { version, message } = decode_cbor(bytes)?;
+
+    // Envelopes of newer versions are decoded as best as we can, relying on their unknown fields
+    // getting ignored.
+    if version > ENVELOPE_VERSION {
+        println!("decoding envelope of newer version {version}");
+    }
+
+    let payload = decode_cbor(&message.payload[..])?;
+    Ok(SignedPayload {
+        public_key: message.public_key,
+        payload,
+        payload_bytes: message.payload,
+        signature: message.signature,
+    })
+}
+
+/// Serializes bytes as a CBOR byte string instead of an array of numbers.
+mod byte_string {
+    use std::fmt;
+
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(ByteStringVisitor)
+    }
+
+    struct ByteStringVisitor;
+
+    impl<'de> Visitor<'de> for ByteStringVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a byte string")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: usize = 64 * 1024;
+
+    fn round_trip(payload: Payload) {
+        let private_key = PrivateKey::new();
+        let bytes = encode_payload(&private_key, payload.clone());
+
+        let signed = decode_payload(&bytes, LIMIT).expect("decode payload");
+        assert!(signed.verify());
+        assert_eq!(signed.public_key, private_key.public_key());
+        assert_eq!(signed.payload, payload);
+    }
+
+    #[test]
+    fn trigger_round_trip() {
+        round_trip(Payload::Trigger {
+            timestamp: 1_700_000_000_000,
+            sample_index: 7,
+            seq: 42,
+            bank_id: 3,
+        });
+    }
+
+    #[test]
+    fn replay_round_trip() {
+        let author = PrivateKey::new();
+        let message = encode_payload(
+            &author,
+            Payload::Trigger {
+                timestamp: 1_700_000_000_000,
+                sample_index: 7,
+                seq: 42,
+                bank_id: 0,
+            },
+        );
+        round_trip(Payload::Replay {
+            message: message.clone(),
+        });
+
+        // The replayed trigger still verifies as published by its author.
+        let replayed = decode_payload(&message, LIMIT).expect("decode replayed trigger");
+        assert!(replayed.verify());
+        assert_eq!(replayed.public_key, author.public_key());
+    }
+
+    #[test]
+    fn control_round_trip() {
+        round_trip(Payload::Control(ControlKind::AllNotesOff));
+    }
+
+    #[test]
+    fn compressed_raw_round_trip() {
+        // Large enough to be compressed.
+        round_trip(Payload::Raw(vec![3; 4096]));
+    }
+
+    #[test]
+    fn tampered_payload_fails_verification() {
+        let private_key = PrivateKey::new();
+        let heartbeat = Payload::Heartbeat {
+            timestamp: 1,
+            listener: false,
+        };
+        let mut signed = SignedPayload::new(&private_key, heartbeat);
+        assert!(signed.verify());
+
+        signed.payload = Payload::Heartbeat {
+            timestamp: 2,
+            listener: false,
+        };
+        assert!(!signed.verify());
+    }
+
+    /// Triggers as published by versions which knew other fields than we do.
+    #[derive(Serialize)]
+    enum OtherVersionPayload {
+        Trigger {
+            timestamp: u64,
+            sample_index: u16,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            velocity: Option<u8>,
+        },
+    }
+
+    fn decode_other_version(payload: OtherVersionPayload) -> SignedPayload {
+        let private_key = PrivateKey::new();
+        let payload_bytes = encode_cbor(&payload).expect("encode other version payload");
+        // Signed as is, like a peer knowing these fields would.
+        let payload = decode_cbor(&payload_bytes[..]).expect("decode other version payload");
+        let signed = SignedPayload::sign(&private_key, payload, payload_bytes);
+        let bytes = encode_signed(signed);
+
+        decode_payload(&bytes, LIMIT).expect("decode payload")
+    }
+
+    #[test]
+    fn payload_of_older_version_verifies() {
+        // Published before triggers had sequence numbers and banks.
+        let signed = decode_other_version(OtherVersionPayload::Trigger {
+            timestamp: 1_700_000_000_000,
+            sample_index: 7,
+            velocity: None,
+        });
+
+        assert!(signed.verify());
+        assert_eq!(
+            signed.payload,
+            Payload::Trigger {
+                timestamp: 1_700_000_000_000,
+                sample_index: 7,
+                seq: 0,
+                bank_id: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn payload_of_newer_version_verifies() {
+        let signed = decode_other_version(OtherVersionPayload::Trigger {
+            timestamp: 1_700_000_000_000,
+            sample_index: 7,
+            velocity: Some(100),
+        });
+
+        assert!(signed.verify());
+        assert!(matches!(
+            signed.payload,
+            Payload::Trigger {
+                sample_index: 7,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn oversized_payload_is_rejected() {
+        let bytes = encode_payload(&PrivateKey::new(), Payload::Raw(vec![0; 4096]));
+
+        assert!(matches!(
+            decode_payload(&bytes, 1024),
+            Err(WireError::Compression(_))
+        ));
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert!(decode_payload(&[], LIMIT).is_err());
+        assert!(decode_payload(&[0, 0xff, 0xff, 0xff], LIMIT).is_err());
+        assert!(decode_payload(&[9, 1, 2, 3], LIMIT).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use can_you_peer_me_lib::{
+    ApplicationMessage, ChannelEvent, Config, CustomDiscovery, Delivery, Node, PublishError, Role,
+    Room, SelfTestStage, StageOutcome,
+};
+use futures_util::stream::{self, Stream, StreamExt};
+use p2panda_core::PrivateKey;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connecting to a peer and joining its topic can take a while, especially on busy machines.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn spawn_local_node() -> (Node, mpsc::Receiver<ChannelEvent>) {
+    let config = Config {
+        local_only: true,
+        ..Config::default()
+    };
+    Node::spawn(config, PrivateKey::new(), Room::default()).await
+}
+
+/// Two networked nodes, the second one connecting to the first via a static peer list.
+///
+/// mdns is off, so this works without multicast and nodes of other runs or apps on the same
+/// machine don't interfere.
+async fn spawn_static_pair() -> (
+    (Node, mpsc::Receiver<ChannelEvent>),
+    (Node, mpsc::Receiver<ChannelEvent>),
+) {
+    let config = Config {
+        local_only: false,
+        mdns: false,
+        mdns_name: Some(format!("test-{}", PrivateKey::new().public_key())),
+        ..Config::default()
+    };
+    let first_key = PrivateKey::new();
+    let first = Node::spawn(config.clone(), first_key.clone(), Room::default()).await;
+
+    // The endpoint binds to the unspecified address, peers on this machine reach it on loopback.
+    let addresses = first
+        .0
+        .diagnostics()
+        .await
+        .expect("first node diagnostics")
+        .listening_addresses
+        .iter()
+        .map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .expect("parse listening address")
+        })
+        .filter(|address| address.is_ipv4())
+        .map(|address| SocketAddr::from((Ipv4Addr::LOCALHOST, address.port())))
+        .collect::<Vec<_>>();
+    assert!(!addresses.is_empty(), "first node is listening");
+
+    let config = Config {
+        custom_discovery: Some(CustomDiscovery::static_peers(vec![(
+            first_key.public_key(),
+            addresses,
+        )])),
+        ..config
+    };
+    let second = Node::spawn(config, PrivateKey::new(), Room::default()).await;
+    (first, second)
+}
+
+fn receiver_stream(
+    events_rx: &mut mpsc::Receiver<ChannelEvent>,
+) -> impl Stream<Item = ChannelEvent> + Unpin + '_ {
+    stream::poll_fn(move |cx| events_rx.poll_recv(cx))
+}
+
+/// Sample index of the next trigger on the stream, skipping all other events.
+async fn next_trigger(events: &mut (impl Stream<Item = ChannelEvent> + Unpin)) -> u16 {
+    timeout(EVENT_TIMEOUT, async {
+        loop {
+            match events.next().await.expect("event stream ended") {
+                ChannelEvent::ApplicationMessage(message) => return message.sample_index,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .expect("trigger arrives in time")
+}
+
+#[tokio::test]
+async fn replaced_event_stream_receives_later_triggers() {
+    let (node, mut events_rx) = spawn_local_node().await;
+
+    let mut first = Box::pin(node.events());
+    node.publish(None, 1, Delivery::Gossip)
+        .await
+        .expect("publish first trigger");
+    assert_eq!(next_trigger(&mut first).await, 1);
+
+    // Like a reloading window, the old stream goes away before the new one subscribes.
+    drop(first);
+    let mut second = Box::pin(node.events());
+    node.publish(None, 2, Delivery::Gossip)
+        .await
+        .expect("publish second trigger");
+    assert_eq!(next_trigger(&mut second).await, 2);
+
+    // The receiver returned by `spawn` sees everything, regardless of the swap.
+    let mut received = receiver_stream(&mut events_rx);
+    assert_eq!(next_trigger(&mut received).await, 1);
+    assert_eq!(next_trigger(&mut received).await, 2);
+}
+
+#[tokio::test]
+async fn triggers_without_any_stream_are_not_replayed() {
+    let (node, _events_rx) = spawn_local_node().await;
+
+    // Nobody is subscribed, the node must not stall or panic.
+    node.publish(None, 1, Delivery::Gossip)
+        .await
+        .expect("publish unobserved trigger");
+
+    let mut events = Box::pin(node.events());
+    node.publish(None, 2, Delivery::Gossip)
+        .await
+        .expect("publish observed trigger");
+    assert_eq!(next_trigger(&mut events).await, 2);
+}
+
+#[tokio::test]
+async fn history_is_restored_after_clean_shutdown() {
+    let dir = std::env::temp_dir().join(format!(
+        "can-you-peer-me-history-{}",
+        PrivateKey::new().public_key()
+    ));
+    let config = Config {
+        local_only: true,
+        history_dir: Some(dir.clone()),
+        ..Config::default()
+    };
+    let spawn = || Node::spawn(config.clone(), PrivateKey::new(), Room::default());
+
+    let (node, _events_rx) = spawn().await;
+    for index in 0..3 {
+        node.publish(None, index, Delivery::Gossip)
+            .await
+            .expect("publish trigger");
+    }
+    let mut events = Box::pin(node.events());
+    node.publish(None, 3, Delivery::Gossip)
+        .await
+        .expect("publish last trigger");
+    // Once the last echo arrived, all triggers are in the history.
+    assert_eq!(next_trigger(&mut events).await, 3);
+
+    let saved = node.recent_triggers().await;
+    node.shutdown().await;
+
+    let (restarted, _events_rx) = spawn().await;
+    let restored = restarted.recent_triggers().await;
+
+    let key = |trigger: &ApplicationMessage| (trigger.timestamp, trigger.sample_index, trigger.seq);
+    assert_eq!(saved.len(), 4);
+    assert_eq!(
+        restored.iter().map(key).collect::<Vec<_>>(),
+        saved.iter().map(key).collect::<Vec<_>>()
+    );
+
+    // Cleared triggers stay gone after the next restart.
+    restarted.clear_history().await.expect("clear history");
+    drop(restarted);
+    let (cleared, _events_rx) = spawn().await;
+    assert!(cleared.recent_triggers().await.is_empty());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn self_test_of_local_node_passes_the_round_trip() {
+    let (node, _events_rx) = spawn_local_node().await;
+
+    let report = node.self_test().await.expect("run self test");
+    let outcomes = report
+        .stages
+        .iter()
+        .map(|stage| (stage.stage, stage.outcome))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        outcomes,
+        vec![
+            (SelfTestStage::NetworkBuilt, StageOutcome::Skipped),
+            (SelfTestStage::Subscribed, StageOutcome::Skipped),
+            (SelfTestStage::TopicReady, StageOutcome::Skipped),
+            (SelfTestStage::PublishRoundTrip, StageOutcome::Passed),
+        ]
+    );
+    assert!(report.passed);
+}
+
+#[tokio::test]
+async fn listeners_reject_publishing() {
+    let (node, _events_rx) = spawn_local_node().await;
+
+    node.set_role(Role::Listener).await;
+    let result = node.publish(None, 1, Delivery::Gossip).await;
+    assert!(matches!(result, Err(PublishError::ReadOnly)));
+
+    node.set_role(Role::Performer).await;
+    let mut events = Box::pin(node.events());
+    node.publish(None, 2, Delivery::Gossip)
+        .await
+        .expect("publish as performer");
+    assert_eq!(next_trigger(&mut events).await, 2);
+}
+
+#[tokio::test]
+async fn discovered_peers_reach_the_frontend_as_system_events() {
+    let ((_node, mut events_rx), (other, _other_events_rx)) = spawn_static_pair().await;
+
+    let other_key = other
+        .diagnostics()
+        .await
+        .expect("other node diagnostics")
+        .public_key;
+    let other_peer = serde_json::to_value(other_key).expect("serialize public key");
+    let mut events = receiver_stream(&mut events_rx);
+    let event = timeout(DISCOVERY_TIMEOUT, async {
+        loop {
+            let event = events.next().await.expect("event stream ended");
+            if !matches!(event, ChannelEvent::SystemEvent(_)) {
+                continue;
+            }
+            let event = serde_json::to_value(&event).expect("serialize channel event");
+            let system_event = &event["data"];
+            if matches!(
+                system_event["type"].as_str(),
+                Some("PeerDiscovered" | "GossipNeighborUp")
+            ) && system_event["peer"] == other_peer
+            {
+                return event;
+            }
+        }
+    })
+    .await
+    .expect("other node is discovered in time");
+
+    assert_eq!(event["type"], "SystemEvent");
+    let system_event = event["data"]
+        .as_object()
+        .expect("system event is an object");
+    match system_event["type"].as_str() {
+        Some("PeerDiscovered") => {
+            assert_eq!(system_event.len(), 2);
+        }
+        Some("GossipNeighborUp") => {
+            assert_eq!(system_event.len(), 3);
+            assert!(!system_event["topic_id"].is_null());
+        }
+        other => panic!("unexpected system event {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn statically_configured_peers_exchange_triggers() {
+    let ((_first, mut first_events_rx), (second, _second_events_rx)) = spawn_static_pair().await;
+
+    // Published before the topic is ready, the trigger is queued until the peers are connected.
+    second
+        .publish(None, 7, Delivery::Gossip)
+        .await
+        .expect("publish to the static peer");
+
+    let mut events = receiver_stream(&mut first_events_rx);
+    let sample_index = timeout(DISCOVERY_TIMEOUT, async {
+        loop {
+            match events.next().await.expect("event stream ended") {
+                ChannelEvent::ApplicationMessage(message) => return message.sample_index,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .expect("trigger arrives from the static peer in time");
+    assert_eq!(sample_index, 7);
+}